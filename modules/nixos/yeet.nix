@@ -37,6 +37,50 @@ in
       description = "ED25519 key used as the hosts identity";
     };
 
+    caBundle = mkOption {
+      type = types.nullOr types.path;
+      default = null;
+      description = "PEM bundle of additional CA certificates to trust when connecting to the server";
+    };
+
+    enrollmentTokenFile = mkOption {
+      type = types.nullOr types.str;
+      default = null;
+      example = "/run/secrets/yeet-enrollment-token";
+      description = ''
+        File with an enrollment token to enroll without a verification code. Not a path in the nix store
+        since the token is a secret. Without it the token is read from `yeet.enrollment_token=` on the
+        kernel command line
+      '';
+    };
+
+    healthChecks = {
+      failedUnits = mkOption {
+        type = types.bool;
+        default = false;
+        description = "Roll back if a systemd unit failed after switching to a new version";
+      };
+
+      server = mkOption {
+        type = types.bool;
+        default = false;
+        description = "Roll back if the yeet server is not reachable after switching to a new version";
+      };
+
+      commands = mkOption {
+        type = types.listOf types.str;
+        default = [ ];
+        example = [ "curl --fail http://localhost:8080/health" ];
+        description = "Shell commands which have to succeed after switching to a new version";
+      };
+
+      timeout = mkOption {
+        type = types.int;
+        default = 60;
+        description = "Seconds to wait for failing health checks to recover before rolling back";
+      };
+    };
+
     package = mkPackageOption pkgs "yeet" { };
   };
 
@@ -65,7 +109,13 @@ in
         RestartSec = 5;
         RuntimeDirectory = "yeet";
         ExecStart = ''
-          ${lib.getExe cfg.package} agent --sleep ${toString cfg.sleep} --server ${cfg.server} --key ${cfg.key} ${lib.optionalString cfg.facter "--facter"}
+          ${lib.getExe cfg.package} agent --sleep ${toString cfg.sleep} --server ${cfg.server} --key ${cfg.key} ${lib.optionalString cfg.facter "--facter"} \
+            ${lib.optionalString (cfg.caBundle != null) "--ca-bundle ${cfg.caBundle}"} \
+            ${lib.optionalString (cfg.enrollmentTokenFile != null) "--enrollment-token-file ${cfg.enrollmentTokenFile}"} \
+            ${lib.optionalString cfg.healthChecks.failedUnits "--check-failed-units"} \
+            ${lib.optionalString cfg.healthChecks.server "--check-server"} \
+            --check-timeout ${toString cfg.healthChecks.timeout} \
+            ${lib.concatMapStringsSep " " (command: "--check-command ${lib.escapeShellArg command}") cfg.healthChecks.commands}
         '';
       };
     };
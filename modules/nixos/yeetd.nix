@@ -7,6 +7,7 @@
 with lib;
 let
   cfg = config.services.yeetd;
+  settingsFormat = pkgs.formats.toml { };
 in
 {
   meta.maintainers = [ lib.maintainers.Srylax ];
@@ -33,12 +34,33 @@ in
       '';
     };
 
+    storage = lib.mkOption {
+      type = lib.types.enum [
+        "json"
+        "sqlite"
+      ];
+      default = "json";
+      description = "Storage backend for the yeetd state. `sqlite` only writes the hosts that changed";
+    };
+
     stateLocation = lib.mkOption {
       type = lib.types.str;
       default = "/var/lib/yeetd/state.json";
       description = "Location where yeetd state is stored";
     };
 
+    auditLocation = lib.mkOption {
+      type = lib.types.str;
+      default = "/var/lib/yeetd/audit.jsonl";
+      description = "Location of the append-only audit log";
+    };
+
+    snapshots = lib.mkOption {
+      type = lib.types.ints.unsigned;
+      default = 5;
+      description = "Number of previous state versions to keep next to the state (JSON storage only). Taken on startup and then hourly";
+    };
+
     initKey = lib.mkOption {
       type = lib.types.path;
       description = ''
@@ -47,6 +69,59 @@ in
       '';
     };
 
+    webhooks = {
+      urls = lib.mkOption {
+        type = lib.types.listOf lib.types.str;
+        default = [ ];
+        description = "URLs yeetd POSTs fleet events to";
+      };
+
+      key = lib.mkOption {
+        type = lib.types.nullOr lib.types.path;
+        default = null;
+        description = "Private key the events are signed with. Required if there are webhook URLs";
+      };
+
+      staleAfter = lib.mkOption {
+        type = lib.types.ints.positive;
+        default = 15;
+        description = "Minutes after which a host that did not check in is reported as stale";
+      };
+    };
+
+    metricsTokenFile = lib.mkOption {
+      type = lib.types.nullOr lib.types.str;
+      default = null;
+      description = "File with the bearer token Prometheus scrapes `/metrics` with. The endpoint is disabled if not set";
+    };
+
+    settings = lib.mkOption {
+      type = settingsFormat.type;
+      default = { };
+      example = {
+        listen = [
+          "0.0.0.0:4337"
+          "[::]:4337"
+        ];
+        max_attempts = 5;
+      };
+      description = "Additional yeetd configuration. The options above take precedence";
+    };
+
+    tls = {
+      cert = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "PEM certificate chain. yeetd serves HTTPS if set. Reload with `systemctl reload yeetd`";
+      };
+
+      key = lib.mkOption {
+        type = lib.types.nullOr lib.types.str;
+        default = null;
+        description = "PEM private key of the certificate";
+      };
+    };
+
     openFirewall = lib.mkOption {
       type = lib.types.bool;
       default = false;
@@ -79,12 +154,22 @@ in
       environment.YEET_PORT = "${cfg.port}";
       environment.YEET_HOST = "${cfg.host}";
       environment.YEET_STATE = "${cfg.stateLocation}";
+      environment.YEET_STORAGE = "${cfg.storage}";
+      environment.YEET_SNAPSHOTS = toString cfg.snapshots;
+      environment.YEET_AUDIT = "${cfg.auditLocation}";
       environment.YEET_INIT_KEY = "${cfg.initKey}";
+      environment.YEET_WEBHOOKS = concatStringsSep "," cfg.webhooks.urls;
+      environment.YEET_WEBHOOK_STALE_AFTER = toString cfg.webhooks.staleAfter;
+      environment.YEET_TLS_CERT = mkIf (cfg.tls.cert != null) cfg.tls.cert;
+      environment.YEET_TLS_KEY = mkIf (cfg.tls.key != null) cfg.tls.key;
+      environment.YEET_WEBHOOK_KEY = mkIf (cfg.webhooks.key != null) "${cfg.webhooks.key}";
+      environment.YEET_METRICS_TOKEN = mkIf (cfg.metricsTokenFile != null) cfg.metricsTokenFile;
 
       serviceConfig = {
         User = cfg.user;
         Group = cfg.group;
-        ExecStart = "${lib.getExe cfg.package}";
+        ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
+        ExecStart = "${lib.getExe cfg.package} --config ${settingsFormat.generate "yeetd.toml" cfg.settings}";
       };
     };
   };
@@ -1,8 +1,9 @@
 use std::{
-    fs::File,
+    collections::BTreeSet,
+    fs::{self, File},
     io::{self, BufRead as _, BufReader, Write as _},
     path::Path,
-    process::Command,
+    process::{Command, Output},
     sync::OnceLock,
     time::Duration,
 };
@@ -11,20 +12,29 @@ use api::key::{get_secret_key, get_verify_key};
 use backon::{ConstantBuilder, Retryable as _};
 use ed25519_dalek::VerifyingKey;
 use httpsig_hyper::prelude::SecretKey;
-use log::{error, info};
-use rootcause::{Report, bail, prelude::ResultExt as _, report};
+use log::{error, info, warn};
+use rootcause::{Report, bail, prelude::ResultExt as _};
 use tempfile::NamedTempFile;
 use tokio::time;
 use yeet::{nix, server};
 
-use crate::{cli_args::AgentConfig, notification, varlink, version::get_active_version};
+use crate::{
+    cli_args::AgentConfig, health, notification, varlink, version::get_active_version,
+};
 
 static VERIFICATION_CODE: OnceLock<u32> = OnceLock::new();
 
+/// Lines of stderr sent to the server when a deployment fails
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Kernel command line parameter with an enrollment token e.g. for machines booted from an image
+const TOKEN_PARAMETER: &str = "yeet.enrollment_token=";
+
 /// When running the agent should do these things in order:
 /// 1. Check if agent is active aka if the key is enrolled with `/system/verify`
 ///     if not:
-///         create a new verification request
+///         enroll with the enrollment token if there is one
+///         otherwise create a new verification request
 ///         pull the verify endpoint in a time intervall
 /// 2. Continuosly pull the system endpoint and execute based on the provided
 pub async fn agent(config: &AgentConfig, sleep: u64, facter: bool) -> Result<(), Report> {
@@ -81,43 +91,111 @@ async fn agent_loop(
             None
         };
 
-        let code = server::system::add_verification_attempt(
-            &config.server,
-            &api::VerificationAttempt {
-                key: pub_key,
-                store_path: get_active_version()?,
-                artifacts: api::VerificationArtifacts { nixos_facter },
-            },
-        )
-        .await?;
-        let _ = VERIFICATION_CODE.set(code);
-        info!("Your verification code is: {code}");
-        bail!("Waiting for verification");
+        let artifacts = api::VerificationArtifacts { nixos_facter };
+
+        let enrolled = match enrollment_token(config)? {
+            Some(token) => {
+                let enrollment = api::enroll::Enrollment {
+                    token,
+                    key: pub_key,
+                    store_path: get_active_version()?,
+                    hostname: system_hostname(),
+                    artifacts: artifacts.clone(),
+                };
+                match server::system::enroll(&config.server, &enrollment).await {
+                    Ok(hostname) => {
+                        info!("Enrolled as {hostname}");
+                        true
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Could not enroll with the token - requesting a verification code: {err}"
+                        );
+                        false
+                    }
+                }
+            }
+            None => false,
+        };
+
+        if !enrolled {
+            let store_path = get_active_version()?;
+            let code = server::system::add_verification_attempt(
+                &config.server,
+                &api::VerificationAttempt {
+                    key: pub_key,
+                    configuration: api::configuration_name(&store_path),
+                    store_path,
+                    artifacts,
+                    hostname: system_hostname(),
+                },
+            )
+            .await?;
+            let _ = VERIFICATION_CODE.set(code);
+            info!("Your verification code is: {code}");
+            bail!("Waiting for verification");
+        }
     }
     info!("Verified!");
 
+    let mut long_poll = true;
     loop {
-        let action = server::system::check(
-            &config.server,
-            key,
-            &api::VersionRequest {
-                store_path: get_active_version()?,
-            },
-        )
-        .await?;
+        let version = api::VersionRequest {
+            store_path: get_active_version()?,
+        };
+
+        let action = if long_poll {
+            server::system::check_wait(&config.server, key, &version).await?
+        } else {
+            None
+        };
+        // The server already waited for a change - ask again right away if there was none
+        let waited = action.is_some();
+        let action = match action {
+            Some(action) => action,
+            None => {
+                if long_poll {
+                    warn!("Server does not support long polling - polling every {sleep}s");
+                    long_poll = false;
+                }
+                server::system::check(&config.server, key, &version).await?
+            }
+        };
 
         info!("{action:#?}");
 
-        agent_action(action)?;
-        time::sleep(Duration::from_secs(sleep)).await;
+        let idle = matches!(action, api::AgentAction::Nothing | api::AgentAction::Detach);
+        agent_action(config, key, action).await?;
+        if !(waited && idle) {
+            time::sleep(Duration::from_secs(sleep)).await;
+        }
     }
 }
 
-fn agent_action(action: api::AgentAction) -> Result<(), Report> {
+async fn agent_action(
+    config: &AgentConfig,
+    key: &SecretKey,
+    action: api::AgentAction,
+) -> Result<(), Report> {
     match action {
         api::AgentAction::Nothing => {}
         api::AgentAction::Detach => {}
-        api::AgentAction::SwitchTo(remote_store_path) => update(&remote_store_path)?,
+        api::AgentAction::SwitchTo(remote_store_path) => {
+            let report = update(config, key, &remote_store_path).await?;
+            if report.outcome != api::DeploymentOutcome::Switched {
+                error!(
+                    "Update to {} failed ({:?}):\n{}",
+                    report.store_path, report.outcome, report.stderr_tail
+                );
+            }
+            // The server only learns about the outcome - keep polling even if it is unreachable
+            if let Err(err) = server::system::report(&config.server, key, &report).await {
+                warn!(
+                    "Could not report the deployment of {}:\n{err}",
+                    report.store_path
+                );
+            }
+        }
     }
     Ok(())
 }
@@ -137,20 +215,118 @@ fn trusted_public_keys() -> Result<Vec<String>, Report> {
         .collect())
 }
 
-fn update(version: &api::RemoteStorePath) -> Result<(), Report> {
-    download(version)?;
-    activate(&version.store_path)?;
-    notification::notify_all()?;
-    Ok(())
+/// Download and activate the version. A failing command is part of the report and not an error.
+/// If the activation or a health check fails the previous version is activated again
+async fn update(
+    config: &AgentConfig,
+    key: &SecretKey,
+    version: &api::RemoteStorePath,
+) -> Result<api::DeploymentReport, Report> {
+    let failed = |outcome, output: &Output| api::DeploymentReport {
+        store_path: version.store_path.clone(),
+        outcome,
+        exit_code: output.status.code(),
+        stderr_tail: stderr_tail(&output.stderr),
+        rolled_back: false,
+    };
+
+    let previous = get_active_version()?;
+    let failed_before = if config.health.failed_units {
+        health::failed_units()?
+    } else {
+        BTreeSet::new()
+    };
+
+    let download = download(version)?;
+    if !download.status.success() {
+        return Ok(failed(api::DeploymentOutcome::DownloadFailed, &download));
+    }
+
+    let activation = activate(&version.store_path)?;
+    if !activation.status.success() {
+        return Ok(api::DeploymentReport {
+            rolled_back: rollback(&previous),
+            ..failed(api::DeploymentOutcome::ActivationFailed, &activation)
+        });
+    }
+
+    let failures = health::check(config, key, &failed_before).await;
+    if !failures.is_empty() {
+        return Ok(api::DeploymentReport {
+            store_path: version.store_path.clone(),
+            outcome: api::DeploymentOutcome::HealthCheckFailed,
+            exit_code: None,
+            stderr_tail: failures.join("\n"),
+            rolled_back: rollback(&previous),
+        });
+    }
+
+    notification::notify_all(version.commit.as_ref())?;
+    Ok(api::DeploymentReport {
+        store_path: version.store_path.clone(),
+        outcome: api::DeploymentOutcome::Switched,
+        exit_code: activation.status.code(),
+        stderr_tail: String::new(),
+        rolled_back: false,
+    })
+}
+
+/// Switch back to the version which ran before the update. Returns true if that worked
+fn rollback(previous: &api::StorePath) -> bool {
+    warn!("Rolling back to {previous}");
+    match activate(previous) {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            error!("Could not roll back:\n{}", stderr_tail(&output.stderr));
+            false
+        }
+        Err(err) => {
+            error!("Could not roll back: {err}");
+            false
+        }
+    }
 }
 
 pub fn switch_to(store_path: &api::StorePath) -> Result<(), Report> {
-    activate(store_path)?;
-    notification::notify_all()?;
+    let activation = activate(store_path)?;
+    if !activation.status.success() {
+        bail!("Could not activate {store_path}:\n{}", stderr_tail(&activation.stderr));
+    }
+    notification::notify_all(None)?;
     Ok(())
 }
 
-fn download(version: &api::RemoteStorePath) -> Result<(), Report> {
+/// Token from `--enrollment-token-file` or else from the kernel command line
+fn enrollment_token(config: &AgentConfig) -> Result<Option<String>, Report> {
+    if let Some(path) = &config.enrollment_token_file {
+        let token = fs::read_to_string(path)
+            .context("Could not read the enrollment token")
+            .attach(format!("File: {}", path.display()))?;
+        return Ok(Some(token.trim().to_owned()));
+    }
+
+    let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_default();
+    Ok(cmdline
+        .split_whitespace()
+        .find_map(|parameter| parameter.strip_prefix(TOKEN_PARAMETER))
+        .map(str::to_owned))
+}
+
+fn system_hostname() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|hostname| hostname.trim().to_owned())
+        .filter(|hostname| !hostname.is_empty())
+}
+
+fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines: Vec<_> = stderr.lines().collect();
+    lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n")
+}
+
+/// Realise the version from its substitutor. Only fails if `nix-store` could not be run
+fn download(version: &api::RemoteStorePath) -> Result<Output, Report> {
     info!("Downloading {}", version.store_path);
     let mut keys = trusted_public_keys()?;
     keys.push(version.public_key.clone());
@@ -158,7 +334,8 @@ fn download(version: &api::RemoteStorePath) -> Result<(), Report> {
     keys.dedup();
 
     let mut command = Command::new("nix-store");
-    command.stderr(io::stderr()).stdout(io::stdout());
+    // stderr is captured for the deployment report
+    command.stdout(io::stdout());
     command.args(vec![
         "--realise",
         &version.store_path,
@@ -188,57 +365,58 @@ fn download(version: &api::RemoteStorePath) -> Result<(), Report> {
         ]);
     }
 
-    let download = command.output()?;
+    let download = command.output().context("Could not run nix-store")?;
 
     if !download.status.success() {
-        return Err(report!("{}", String::from_utf8(download.stderr)?)
-            .context("Could not realize new version")
-            .attach(format!(
-                "Command: {}",
-                command
-                    .get_args()
-                    .map(|ostr| ostr.to_string_lossy())
-                    .collect::<Vec<_>>()
-                    .join(" ")
-            ))
-            .into_dynamic());
+        error!(
+            "Could not realize new version. Command: nix-store {}",
+            command
+                .get_args()
+                .map(|ostr| ostr.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
     }
-    Ok(())
+    Ok(download)
 }
 
-fn set_system_profile(store_path: &api::StorePath) -> Result<(), Report> {
+fn set_system_profile(store_path: &api::StorePath) -> Result<Output, Report> {
     info!("Setting system profile to {}", store_path);
-    let profile = Command::new("nix-env")
+    Ok(Command::new("nix-env")
         .args([
             "--profile",
             "/nix/var/nix/profiles/system",
             "--set",
             &store_path,
         ])
-        .output()?;
-    if !profile.status.success() {
-        bail!("{}", String::from_utf8(profile.stderr)?);
-    }
-    Ok(())
+        .output()?)
 }
 
+/// Set the profile and activate it. Returns the output of the first command which failed
 #[cfg(target_os = "macos")]
-fn activate(store_path: &api::StorePath) -> Result<(), Report> {
-    set_system_profile(store_path)?;
+fn activate(store_path: &api::StorePath) -> Result<Output, Report> {
+    let profile = set_system_profile(store_path)?;
+    if !profile.status.success() {
+        return Ok(profile);
+    }
     info!("Activating {}", store_path);
-    Command::new(Path::new(&store_path).join("activate"))
-        .spawn()?
-        .wait()?;
-    Ok(())
+    Ok(Command::new(Path::new(&store_path).join("activate"))
+        .stdout(io::stdout())
+        .output()?)
 }
 
+/// Set the profile and activate it. Returns the output of the first command which failed
 #[cfg(target_os = "linux")]
-fn activate(store_path: &api::StorePath) -> Result<(), Report> {
+fn activate(store_path: &api::StorePath) -> Result<Output, Report> {
     info!("Activating {}", store_path);
-    set_system_profile(store_path)?;
-    Command::new(Path::new(&store_path).join("bin/switch-to-configuration"))
-        .arg("switch")
-        .spawn()?
-        .wait()?;
-    Ok(())
+    let profile = set_system_profile(store_path)?;
+    if !profile.status.success() {
+        return Ok(profile);
+    }
+    Ok(
+        Command::new(Path::new(&store_path).join("bin/switch-to-configuration"))
+            .arg("switch")
+            .stdout(io::stdout())
+            .output()?,
+    )
 }
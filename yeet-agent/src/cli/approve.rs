@@ -1,21 +1,27 @@
 use std::{
+    fmt::{self, Display},
     fs::File,
     io::Write as _,
     path::{Path, PathBuf},
 };
 
+use httpsig_hyper::prelude::SecretKey;
 use inquire::validator::Validation;
-use log::info;
-use rootcause::Report;
-use yeet::server;
+use jiff::tz::TimeZone;
+use log::{info, warn};
+use rootcause::{Report, bail};
+use url::Url;
+use yeet::{display, server};
 
-use crate::{cli_args::Config, sig::ssh, varlink};
+use crate::{cli_args::Config, nix, sig::ssh, varlink};
 
 pub async fn approve(
     config: &Config,
     facter_output: Option<PathBuf>,
     code: Option<u32>,
     hostname: Option<String>,
+    flake_path: PathBuf,
+    darwin: bool,
 ) -> Result<(), Report> {
     let agent_url = {
         let agent_config = varlink::config().await;
@@ -38,18 +44,21 @@ pub async fn approve(
         &ssh::key_by_url(domain)?
     };
 
-    let hostname = if let Some(hostname) = hostname {
-        hostname
+    let (code, attempt) = if let Some(code) = code {
+        // Only needed for the proposed hostname
+        let attempt = server::system::pending(url, secret_key)
+            .await
+            .inspect_err(|err| warn!("Could not get the pending verifications: {err}"))
+            .ok()
+            .and_then(|pending| pending.into_iter().find(|attempt| attempt.code == code));
+        (code, attempt)
     } else {
-        // TODO nix select
-        inquire::Text::new("Hostname:").prompt()?
+        let attempt = select_pending(url, secret_key, "Which host do you want to approve>").await?;
+        (attempt.code, Some(attempt))
     };
 
-    let code = if let Some(code) = code {
-        code
-    } else {
-        inquire::CustomType::<u32>::new("Approval code:").prompt()?
-    };
+    let proposed = attempt.and_then(|attempt| attempt.configuration.or(attempt.hostname));
+    let hostname = select_hostname(hostname, proposed, &flake_path, darwin)?;
 
     info!("Approving {hostname} with code {code}...");
 
@@ -97,3 +106,145 @@ pub async fn approve(
     info!("File {} written", facter_output.as_os_str().display());
     Ok(())
 }
+
+pub async fn reject(config: &Config, code: Option<u32>, blocklist: bool) -> Result<(), Report> {
+    let agent_url = {
+        let agent_config = varlink::config().await;
+        if let Err(e) = &agent_config {
+            log::error!("Could not get agent config: {e}")
+        }
+        agent_config.ok().map(|config| config.server)
+    };
+
+    let url = &config
+        .url
+        .clone()
+        .or(agent_url)
+        .ok_or(rootcause::report!("`--url` required for reject"))?;
+
+    let secret_key = {
+        let domain = url
+            .domain()
+            .ok_or(rootcause::report!("Provided URL has no domain part"))?;
+        &ssh::key_by_url(domain)?
+    };
+
+    let code = if let Some(code) = code {
+        code
+    } else {
+        select_pending(url, secret_key, "Which host do you want to reject>")
+            .await?
+            .code
+    };
+
+    info!("Rejecting code {code}...");
+    server::system::reject(
+        url,
+        secret_key,
+        &api::VerificationRejection { code, blocklist },
+    )
+    .await?;
+    if blocklist {
+        info!(
+            "Rejected - the key can no longer enroll until it is removed with `yeet server remove-key`"
+        );
+    } else {
+        info!("Rejected");
+    }
+    Ok(())
+}
+
+/// Entry of the verification attempt picker
+struct Pending(api::PendingVerification);
+
+impl Display for Pending {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fingerprint = api::hash_hex(self.0.key.as_bytes());
+        write!(
+            f,
+            "{} key {} {}",
+            self.0.code,
+            fingerprint.get(..16).unwrap_or(&fingerprint),
+            display::time_diff(
+                &self.0.since.with_time_zone(TimeZone::system()),
+                jiff::Unit::Minute,
+                5_f64,
+                jiff::Unit::Second,
+            ),
+        )?;
+        if let Some(name) = self.0.configuration.as_ref().or(self.0.hostname.as_ref()) {
+            write!(f, " proposes {name}")?;
+        }
+        if let Some(summary) = &self.0.facter_summary {
+            write!(f, " ({summary})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Let the user pick one of the pending verification attempts instead of typing the code
+async fn select_pending(
+    url: &Url,
+    key: &SecretKey,
+    message: &str,
+) -> Result<api::PendingVerification, Report> {
+    let pending = server::system::pending(url, key).await?;
+    if pending.is_empty() {
+        return Err(rootcause::report!(
+            "No pending verification attempts - is the agent running on the new host?"
+        ));
+    }
+    let selected =
+        inquire::Select::new(message, pending.into_iter().map(Pending).collect()).prompt()?;
+    Ok(selected.0)
+}
+
+/// The hostname has to be a configuration of the flake so that the host is published the right
+/// system. Outside of a flake any hostname is accepted
+fn select_hostname(
+    hostname: Option<String>,
+    proposed: Option<String>,
+    flake_path: &Path,
+    darwin: bool,
+) -> Result<String, Report> {
+    let configurations = nix::list_hosts(&flake_path.to_string_lossy(), darwin)
+        .inspect_err(|err| {
+            warn!("Could not list the hosts of the flake - not checking the hostname: {err}")
+        })
+        .ok()
+        .filter(|configurations| !configurations.is_empty());
+
+    match (hostname, configurations) {
+        (Some(hostname), Some(configurations)) if !configurations.contains(&hostname) => {
+            bail!(
+                "{hostname} has no configuration in {}",
+                flake_path.display()
+            )
+        }
+        (Some(hostname), _) => Ok(hostname),
+        (None, Some(configurations)) => {
+            let cursor = match &proposed {
+                Some(proposed) => configurations
+                    .iter()
+                    .position(|configuration| configuration == proposed)
+                    .unwrap_or_else(|| {
+                        warn!(
+                            "The host proposed {proposed} which has no configuration in the flake"
+                        );
+                        0
+                    }),
+                None => 0,
+            };
+            Ok(inquire::Select::new("Hostname:", configurations)
+                .with_starting_cursor(cursor)
+                .prompt()?)
+        }
+        (None, None) => {
+            let mut prompt = inquire::Text::new("Hostname:");
+            if let Some(proposed) = &proposed {
+                prompt = prompt.with_initial_value(proposed);
+            }
+            Ok(prompt.prompt()?)
+        }
+    }
+}
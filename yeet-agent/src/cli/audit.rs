@@ -0,0 +1,66 @@
+use std::path::PathBuf;
+
+use api::key::get_verify_key;
+use console::style;
+use rootcause::Report;
+use yeet::server;
+
+use crate::{
+    cli_args::Config,
+    section::{self, DisplaySectionItem as _},
+    sig::ssh,
+    varlink,
+};
+
+pub async fn audit(
+    config: &Config,
+    host: Option<String>,
+    key: Option<PathBuf>,
+    since: Option<jiff::Timestamp>,
+    until: Option<jiff::Timestamp>,
+) -> Result<(), Report> {
+    let agent_url = {
+        let agent_config = varlink::config().await;
+        if let Err(e) = &agent_config {
+            log::error!("Could not get agent config: {e}")
+        }
+        agent_config.ok().map(|config| config.server)
+    };
+
+    let url = &config
+        .url
+        .clone()
+        .or(agent_url)
+        .ok_or(rootcause::report!("`--url` required for audit"))?;
+
+    let secret_key = {
+        let domain = url
+            .domain()
+            .ok_or(rootcause::report!("Provided URL has no domain part"))?;
+        &ssh::key_by_url(domain)?
+    };
+
+    let key = match key {
+        Some(key) => Some(get_verify_key(key)?),
+        None => None,
+    };
+
+    let entries = server::audit(
+        url,
+        secret_key,
+        &api::AuditQuery {
+            host,
+            key,
+            since,
+            until,
+        },
+    )
+    .await?;
+
+    section::print_sections(&[(
+        style("Audit log:").underlined().to_string(),
+        entries.iter().map(|e| e.as_section_item()).collect(),
+    )]);
+
+    Ok(())
+}
@@ -3,7 +3,12 @@ use log::info;
 use rootcause::Report;
 use yeet::server;
 
-use crate::{cli_args::Config, sig::ssh, varlink};
+use crate::{
+    cli_args::Config,
+    section::{self, DisplaySectionItem as _},
+    sig::ssh,
+    varlink,
+};
 
 pub async fn remove(config: &Config, hostname: Option<String>) -> Result<(), Report> {
     let agent_url = {
@@ -143,3 +148,255 @@ pub async fn rename(
 
     Ok(())
 }
+
+pub async fn tag(
+    config: &Config,
+    hostname: Option<String>,
+    tags: Vec<(String, String)>,
+    remove: Vec<String>,
+) -> Result<(), Report> {
+    let agent_url = {
+        let agent_config = varlink::config().await;
+        if let Err(e) = &agent_config {
+            log::error!("Could not get agent config: {e}")
+        }
+        agent_config.ok().map(|config| config.server)
+    };
+
+    let url = &config
+        .url
+        .clone()
+        .or(agent_url)
+        .ok_or(rootcause::report!("`--url` required for tag"))?;
+
+    let secret_key = {
+        let domain = url
+            .domain()
+            .ok_or(rootcause::report!("Provided URL has no domain part"))?;
+        &ssh::key_by_url(domain)?
+    };
+
+    let hostname = if let Some(hostname) = hostname {
+        hostname
+    } else {
+        let hostnames = {
+            let hosts = server::status(&url, secret_key).await?;
+            let mut hostnames: Vec<_> = hosts.into_iter().map(|h| h.name).collect();
+            hostnames.sort();
+            hostnames
+        };
+
+        inquire::Select::new("Which host do you want to tag>", hostnames).prompt()?
+    };
+
+    info!("Tagging {hostname}...");
+
+    server::host::set_tags(
+        &url,
+        secret_key,
+        &api::HostTagsRequest {
+            hostname,
+            set: tags.into_iter().collect(),
+            remove,
+        },
+    )
+    .await?;
+
+    info!("Done!");
+
+    Ok(())
+}
+
+pub async fn maintenance(
+    config: &Config,
+    hostname: Option<String>,
+    tag: Option<api::TagSelector>,
+    windows: Vec<api::maintenance::MaintenanceWindow>,
+    list: bool,
+) -> Result<(), Report> {
+    let agent_url = {
+        let agent_config = varlink::config().await;
+        if let Err(e) = &agent_config {
+            log::error!("Could not get agent config: {e}")
+        }
+        agent_config.ok().map(|config| config.server)
+    };
+
+    let url = &config
+        .url
+        .clone()
+        .or(agent_url)
+        .ok_or(rootcause::report!("`--url` required for maintenance"))?;
+
+    let secret_key = {
+        let domain = url
+            .domain()
+            .ok_or(rootcause::report!("Provided URL has no domain part"))?;
+        &ssh::key_by_url(domain)?
+    };
+
+    if list {
+        let groups = server::host::group_maintenance(url, secret_key).await?;
+        section::print_sections(&[(
+            style("Maintenance windows:").underlined().to_string(),
+            groups
+                .into_iter()
+                .map(|group| {
+                    let windows: Vec<_> = group.windows.iter().map(ToString::to_string).collect();
+                    (group.selector.to_string(), windows.join("\n"))
+                })
+                .collect(),
+        )]);
+        return Ok(());
+    }
+
+    let target = match (hostname, tag) {
+        (_, Some(selector)) => api::maintenance::MaintenanceTarget::Group(selector),
+        (Some(hostname), None) => api::maintenance::MaintenanceTarget::Host(hostname),
+        (None, None) => {
+            let hostnames = {
+                let hosts = server::status(url, secret_key).await?;
+                let mut hostnames: Vec<_> = hosts.into_iter().map(|h| h.name).collect();
+                hostnames.sort();
+                hostnames
+            };
+
+            api::maintenance::MaintenanceTarget::Host(
+                inquire::Select::new("Which host do you want to restrict>", hostnames).prompt()?,
+            )
+        }
+    };
+
+    if windows.is_empty() {
+        info!("Removing maintenance windows...");
+    } else {
+        info!("Setting maintenance windows...");
+    }
+
+    server::host::set_maintenance(
+        url,
+        secret_key,
+        &api::maintenance::MaintenanceRequest { target, windows },
+    )
+    .await?;
+
+    info!("Done!");
+
+    Ok(())
+}
+
+pub async fn retry(config: &Config, hostname: Option<String>) -> Result<(), Report> {
+    let agent_url = {
+        let agent_config = varlink::config().await;
+        if let Err(e) = &agent_config {
+            log::error!("Could not get agent config: {e}")
+        }
+        agent_config.ok().map(|config| config.server)
+    };
+
+    let url = &config
+        .url
+        .clone()
+        .or(agent_url)
+        .ok_or(rootcause::report!("`--url` required for retry"))?;
+
+    let secret_key = {
+        let domain = url
+            .domain()
+            .ok_or(rootcause::report!("Provided URL has no domain part"))?;
+        &ssh::key_by_url(domain)?
+    };
+
+    let hostname = if let Some(hostname) = hostname {
+        hostname
+    } else {
+        let hostnames = {
+            let hosts = server::status(url, secret_key).await?;
+            let mut hostnames: Vec<_> = hosts
+                .into_iter()
+                .filter(|h| h.failed_version.is_some())
+                .map(|h| h.name)
+                .collect();
+            hostnames.sort();
+            hostnames
+        };
+
+        if hostnames.is_empty() {
+            info!("No host has a failed deployment");
+            return Ok(());
+        }
+
+        inquire::Select::new("Which host should retry its update>", hostnames).prompt()?
+    };
+
+    info!("Retrying the update of {hostname}...");
+
+    server::host::retry(url, secret_key, &api::RetryRequest { hostname }).await?;
+
+    info!("Done!");
+
+    Ok(())
+}
+
+pub async fn placeholder(
+    config: &Config,
+    hostname: Option<String>,
+    tags: Vec<(String, String)>,
+    remove: bool,
+    list: bool,
+) -> Result<(), Report> {
+    let agent_url = {
+        let agent_config = varlink::config().await;
+        if let Err(e) = &agent_config {
+            log::error!("Could not get agent config: {e}")
+        }
+        agent_config.ok().map(|config| config.server)
+    };
+
+    let url = &config
+        .url
+        .clone()
+        .or(agent_url)
+        .ok_or(rootcause::report!("`--url` required for placeholder"))?;
+
+    let secret_key = {
+        let domain = url
+            .domain()
+            .ok_or(rootcause::report!("Provided URL has no domain part"))?;
+        &ssh::key_by_url(domain)?
+    };
+
+    if list {
+        let placeholders = server::host::list_placeholders(url, secret_key).await?;
+        section::print_sections(&[(
+            style("Placeholders:").underlined().to_string(),
+            placeholders
+                .iter()
+                .map(|placeholder| placeholder.as_section_item())
+                .collect(),
+        )]);
+        return Ok(());
+    }
+    let hostname = hostname.ok_or(rootcause::report!("`--name` required"))?;
+
+    if remove {
+        info!("Removing the placeholder of {hostname}...");
+        server::host::remove_placeholder(url, secret_key, &api::HostRemoveRequest { hostname })
+            .await?;
+    } else {
+        info!("Pre-provisioning {hostname}...");
+        server::host::create_placeholder(
+            url,
+            secret_key,
+            &api::PlaceholderRequest {
+                hostname,
+                tags: tags.into_iter().collect(),
+            },
+        )
+        .await?;
+    }
+
+    info!("Done!");
+
+    Ok(())
+}
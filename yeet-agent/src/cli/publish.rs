@@ -1,16 +1,18 @@
 use std::path::PathBuf;
 
-use log::info;
+use log::{error, info, warn};
 use rootcause::{Report, bail, prelude::ResultExt as _, report};
 use tokio::fs::read_to_string;
 use yeet::{cachix, server};
 
-use crate::{cli_args::Config, nix, sig::ssh, varlink};
+use crate::{cli_args::Config, git, nix, sig::ssh, varlink};
 
 pub async fn publish(
     config: &Config,
     path: PathBuf,
     host: Vec<String>,
+    tag: Option<api::TagSelector>,
+    rollout: Option<api::RolloutPlan>,
     netrc: Option<PathBuf>,
     variant: Option<String>,
     darwin: bool,
@@ -62,12 +64,32 @@ pub async fn publish(
             .ok_or(report!("Cachix cache has no public signing keys"))?
     };
 
-    let host = if host.is_empty() {
+    let host = if let Some(selector) = &tag {
+        let selected = server::host::select(&url, secret_key, selector).await?;
+        let available = nix::list_hosts(&path.to_string_lossy(), darwin)?;
+        let (host, missing): (Vec<_>, Vec<_>) = selected
+            .into_iter()
+            .partition(|host| available.contains(host));
+        if !missing.is_empty() {
+            warn!("{missing:?} match `{selector}` but have no configuration in the flake");
+        }
+        host
+    } else if host.is_empty() {
         nix::get_hosts(&path.to_string_lossy(), darwin)?
     } else {
         host
     };
 
+    let commit = git::head_commit(&path).unwrap_or_else(|err| {
+        warn!("Could not get the commit of the flake: {err}");
+        None
+    });
+    if let Some(commit) = &commit
+        && commit.dirty
+    {
+        warn!("Publishing uncommitted changes on top of {commit}");
+    }
+
     info!("Building {host:?}");
 
     let hosts = nix::build_hosts(&path.to_string_lossy(), host, darwin, variant)?;
@@ -80,7 +102,7 @@ pub async fn publish(
 
     cachix::push_paths(hosts.values(), &cachix).await?;
 
-    server::system::update(
+    let response = server::system::update(
         &url,
         secret_key,
         &api::HostUpdateRequest {
@@ -88,8 +110,25 @@ pub async fn publish(
             public_key,
             substitutor: format!("https://{cachix}.cachix.org"),
             netrc,
+            // The hosts of the selector were already resolved to the ones of the flake above
+            selector: None,
+            store_path: None,
+            rollout,
+            commit,
         },
     )
     .await?;
-    Ok(())
+    check_rejected(&response)
+}
+
+/// The server applies an update to all hosts it accepts. Fail if it rejected any so that CI
+/// pipelines notice
+pub fn check_rejected(response: &api::HostUpdateResponse) -> Result<(), Report> {
+    if response.rejected.is_empty() {
+        return Ok(());
+    }
+    for (hostname, reason) in &response.rejected {
+        error!("{hostname} was not updated: {reason}");
+    }
+    bail!("{} host(s) were not updated", response.rejected.len())
 }
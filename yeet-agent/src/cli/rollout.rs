@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use console::style;
+use httpsig_hyper::prelude::SecretKey;
+use log::info;
+use rootcause::Report;
+use url::Url;
+use uuid::Uuid;
+use yeet::server;
+
+use crate::{cli_args::Config, section, sig::ssh, varlink};
+
+async fn server_and_key(config: &Config) -> Result<(Url, SecretKey), Report> {
+    let agent_url = {
+        let agent_config = varlink::config().await;
+        if let Err(e) = &agent_config {
+            log::error!("Could not get agent config: {e}")
+        }
+        agent_config.ok().map(|config| config.server)
+    };
+
+    let url = config
+        .url
+        .clone()
+        .or(agent_url)
+        .ok_or(rootcause::report!("`--url` required for rollout"))?;
+
+    let secret_key = {
+        let domain = url
+            .domain()
+            .ok_or(rootcause::report!("Provided URL has no domain part"))?;
+        ssh::key_by_url(domain)?
+    };
+
+    Ok((url, secret_key))
+}
+
+/// Use the given id or the newest rollout matching `filter`
+async fn resolve_rollout(
+    url: &Url,
+    key: &SecretKey,
+    id: Option<Uuid>,
+    filter: impl Fn(&api::Rollout) -> bool,
+) -> Result<Uuid, Report> {
+    if let Some(id) = id {
+        return Ok(id);
+    }
+    server::rollout::list(url, key)
+        .await?
+        .into_iter()
+        .rfind(|rollout| filter(rollout))
+        .map(|rollout| rollout.id)
+        .ok_or(rootcause::report!("No matching rollout found"))
+}
+
+pub async fn status(config: &Config, all: bool) -> Result<(), Report> {
+    let (url, secret_key) = &server_and_key(config).await?;
+
+    let rollouts: Vec<_> = server::rollout::list(url, secret_key)
+        .await?
+        .into_iter()
+        .filter(|rollout| all || rollout.state.is_active())
+        .collect();
+
+    if rollouts.is_empty() {
+        info!("No active rollouts");
+        return Ok(());
+    }
+
+    let hosts: HashMap<_, _> = server::status(url, secret_key)
+        .await?
+        .into_iter()
+        .map(|host| (host.name.clone(), host))
+        .collect();
+
+    let sections: Vec<_> = rollouts
+        .iter()
+        .map(|rollout| rollout_section(rollout, &hosts))
+        .collect();
+    section::print_sections(&sections);
+
+    Ok(())
+}
+
+pub async fn pause(config: &Config, id: Option<Uuid>) -> Result<(), Report> {
+    let (url, secret_key) = &server_and_key(config).await?;
+    let id = resolve_rollout(url, secret_key, id, |rollout| {
+        rollout.state == api::RolloutState::Running
+    })
+    .await?;
+
+    server::rollout::pause(url, secret_key, id).await?;
+    info!("Paused rollout {id}");
+    Ok(())
+}
+
+pub async fn resume(config: &Config, id: Option<Uuid>) -> Result<(), Report> {
+    let (url, secret_key) = &server_and_key(config).await?;
+    let id = resolve_rollout(url, secret_key, id, |rollout| {
+        rollout.state == api::RolloutState::Paused
+    })
+    .await?;
+
+    server::rollout::resume(url, secret_key, id).await?;
+    info!("Resumed rollout {id}");
+    Ok(())
+}
+
+pub async fn rollback(config: &Config, id: Option<Uuid>, hosts: Vec<String>) -> Result<(), Report> {
+    let (url, secret_key) = &server_and_key(config).await?;
+
+    let target = if hosts.is_empty() {
+        let id = resolve_rollout(url, secret_key, id, |rollout| {
+            rollout.state != api::RolloutState::RolledBack
+        })
+        .await?;
+        api::RollbackTarget::Rollout(id)
+    } else {
+        api::RollbackTarget::Hosts(hosts)
+    };
+
+    let reverted = server::rollout::rollback(url, secret_key, &target).await?;
+    if reverted.is_empty() {
+        info!("No host had a previous version to roll back to");
+    } else {
+        info!("Rolled back {}", reverted.join(", "));
+    }
+    Ok(())
+}
+
+fn rollout_section(rollout: &api::Rollout, hosts: &HashMap<String, api::Host>) -> section::Section {
+    let state = match rollout.state {
+        api::RolloutState::Running => style("Running").blue(),
+        api::RolloutState::Paused => style("Paused").yellow(),
+        api::RolloutState::Completed => style("Completed").green(),
+        api::RolloutState::RolledBack => style("Rolled back").red(),
+    };
+
+    let percent = rollout.plan.waves.get(rollout.wave).copied().unwrap_or(100);
+    let switched = rollout
+        .hosts
+        .iter()
+        .filter(|(name, version)| {
+            hosts
+                .get(*name)
+                .is_some_and(|host| *host.latest_store_path() == version.store_path)
+        })
+        .count();
+
+    section::section!(
+        style(format!("Rollout {}", rollout.id)).underlined() => [
+            "State", state.bold(),
+            "Wave", format!("{}/{} ({percent}%)", rollout.wave + 1, rollout.plan.waves.len()),
+            "Soak", format!("{:#}", rollout.plan.soak),
+            "Wave started", rollout.wave_started.strftime("%F %T"),
+            "Switched", format!("{switched}/{}", rollout.hosts.len()),
+        ]
+    )
+}
@@ -49,6 +49,31 @@ pub struct AgentConfig {
     pub sleep: u64,
     pub facter: bool,
     pub key: PathBuf,
+    pub ca_bundle: Option<PathBuf>,
+    /// Takes precedence over a token on the kernel command line
+    #[serde(default)]
+    pub enrollment_token_file: Option<PathBuf>,
+    pub health: HealthChecks,
+}
+
+#[derive(Args, Serialize, Deserialize, Clone, Debug)]
+/// Checks after switching to a new version. If one fails the previous version is restored
+pub struct HealthChecks {
+    /// Fail if a systemd unit is failed which was not failed before the switch
+    #[arg(long = "check-failed-units")]
+    pub failed_units: bool,
+
+    /// Fail if the yeet server cannot be reached after the switch e.g. due to a broken network config
+    #[arg(long = "check-server")]
+    pub server: bool,
+
+    /// Shell command which has to succeed after the switch. Can be given multiple times
+    #[arg(long = "check-command")]
+    pub commands: Vec<String>,
+
+    /// Seconds to wait for failing checks to recover before rolling back
+    #[arg(long = "check-timeout", default_value = "60")]
+    pub timeout: u64,
 }
 
 #[expect(clippy::doc_markdown, reason = "No Markdown for clap")]
@@ -56,7 +81,11 @@ pub struct AgentConfig {
 pub enum Commands {
     #[command(hide = true)]
     /// Used to notify all users
-    Notify,
+    Notify {
+        /// Commit the system was updated to
+        #[arg(long)]
+        commit: Option<String>,
+    },
     Agent {
         /// URL of the Yeet Server
         #[arg(long)]
@@ -74,8 +103,24 @@ pub enum Commands {
         /// Collect facter with nixos-facter
         #[arg(long)]
         facter: bool,
+
+        /// PEM bundle of CA certificates to trust in addition to the system ones.
+        /// Needed if the server uses a self-signed or private CA certificate
+        #[arg(long)]
+        ca_bundle: Option<PathBuf>,
+
+        /// File with an enrollment token to enroll without a verification code.
+        /// Without it the token is read from `yeet.enrollment_token=` on the kernel command line
+        #[arg(long)]
+        enrollment_token_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        health: HealthChecks,
     },
     /// Approve a pending key verification with the corresponding code
+    ///
+    /// Without a code the pending verifications are offered to pick from. The hostname is
+    /// proposed by the host and has to be a configuration of the flake
     Approve {
         /// Hostname
         #[arg(index = 1)]
@@ -86,6 +131,26 @@ pub enum Commands {
         /// Facter output file
         #[arg(long)]
         facter: Option<PathBuf>,
+        /// Path to the flake the hostname has to be a configuration of
+        #[arg(long, default_value = current_dir().unwrap().into_os_string())]
+        path: PathBuf,
+        /// Check the hostname against the darwin configurations. Defaults to current ARCH
+        #[arg(
+            long,
+            default_value_t = std::env::consts::ARCH == "aarch64",
+            default_missing_value = (std::env::consts::ARCH == "aarch64").to_string(),
+            num_args = 0..=1,
+            require_equals = false)]
+        darwin: bool,
+    },
+    /// Reject a pending key verification
+    Reject {
+        /// Verification code. Pick from the pending verifications if omitted
+        #[arg(index = 1)]
+        code: Option<u32>,
+        /// Block the key from further verification attempts and enrollments
+        #[arg(long)]
+        blocklist: bool,
     },
     /// Build and then publish some or all hosts in a flake
     Publish {
@@ -97,6 +162,18 @@ pub enum Commands {
         #[arg(long)]
         host: Vec<String>,
 
+        /// Build and publish all hosts matching the tags e.g. `role=web,site=zurich`
+        #[arg(long, conflicts_with = "host")]
+        tag: Option<api::TagSelector>,
+
+        /// Ship the update in waves with the cumulative percentage of hosts e.g. `5,25,100`
+        #[arg(long, value_delimiter = ',')]
+        waves: Vec<u8>,
+
+        /// How long a wave has to run the new version before the next one starts e.g. `30m`
+        #[arg(long, requires = "waves", default_value = "30m")]
+        soak: jiff::SignedDuration,
+
         /// netrc File to use when downloading from the cache. Useful when using private caches
         #[arg(long)]
         netrc: Option<PathBuf>,
@@ -154,9 +231,55 @@ pub enum Commands {
         full: bool,
     },
 
+    /// Show the audit log of all admin and build actions
+    Audit {
+        /// Only actions affecting this host
+        #[arg(long)]
+        host: Option<String>,
+        /// Only actions signed by this public key
+        #[arg(long)]
+        key: Option<PathBuf>,
+        /// Only actions at or after this time e.g. `2025-01-31T12:00:00Z`
+        #[arg(long)]
+        since: Option<jiff::Timestamp>,
+        /// Only actions at or before this time e.g. `2025-01-31T12:00:00Z`
+        #[arg(long)]
+        until: Option<jiff::Timestamp>,
+    },
+
     /// These are the raw subcommands to execute functions on the server
     Server(ServerArgs),
     Host(HostArgs),
+    Rollout(RolloutArgs),
+}
+
+#[derive(Args)]
+pub struct RolloutArgs {
+    #[command(subcommand)]
+    pub command: RolloutCommands,
+}
+
+#[derive(Subcommand)]
+pub enum RolloutCommands {
+    /// Show all rollouts and how far they progressed
+    Status {
+        /// Also show completed rollouts
+        #[arg(long)]
+        all: bool,
+    },
+    /// Stop starting new waves. Defaults to the newest running rollout
+    Pause { id: Option<uuid::Uuid> },
+    /// Continue a paused rollout. Defaults to the newest paused rollout
+    Resume { id: Option<uuid::Uuid> },
+    /// Revert hosts to their previous version.
+    /// Defaults to all hosts of the newest rollout which was not rolled back yet
+    Rollback {
+        id: Option<uuid::Uuid>,
+
+        /// Revert these hosts instead of a rollout
+        #[arg(long, conflicts_with = "id")]
+        host: Vec<String>,
+    },
 }
 #[derive(Args)]
 pub struct HostArgs {
@@ -181,6 +304,59 @@ pub enum HostCommands {
         #[arg(long)]
         name: Option<String>,
     },
+    /// Add or remove tags of a host
+    Tag {
+        /// The name of the host
+        #[arg(long)]
+        name: Option<String>,
+        /// Tags to set in the form `key=value`
+        #[arg(value_parser = parse_tag)]
+        tags: Vec<(String, String)>,
+        /// Keys of tags to remove
+        #[arg(long)]
+        remove: Vec<String>,
+    },
+    /// Pre-provision a host before it enrolls. `yeet publish` reaches the placeholder from then on
+    Placeholder {
+        /// The name of the host
+        #[arg(long, required_unless_present = "list")]
+        name: Option<String>,
+        /// Tags the host gets once it enrolls in the form `key=value`
+        #[arg(value_parser = parse_tag)]
+        tags: Vec<(String, String)>,
+        /// Remove the placeholder instead
+        #[arg(long, requires = "name")]
+        remove: bool,
+        /// Show all placeholders instead
+        #[arg(long, conflicts_with_all = ["name", "tags", "remove"])]
+        list: bool,
+    },
+    /// Issue a version again which failed to deploy on the host
+    Retry {
+        /// The name of the host
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Restrict when hosts switch to a new version. Without `--window` the windows are removed
+    Maintenance {
+        /// The name of the host
+        #[arg(long)]
+        name: Option<String>,
+        /// Set the windows of all hosts matching the tags e.g. `role=db`.
+        /// Windows of the host itself take precedence
+        #[arg(long, conflicts_with = "name")]
+        tag: Option<api::TagSelector>,
+        /// Window in the form `<days> <start>-<end> [<timezone>]` e.g. `sun 22:00-04:00 Europe/Zurich`
+        #[arg(long)]
+        window: Vec<api::maintenance::MaintenanceWindow>,
+        /// Show the windows of all groups instead
+        #[arg(long, conflicts_with_all = ["name", "tag", "window"])]
+        list: bool,
+    },
+}
+
+fn parse_tag(tag: &str) -> Result<(String, String), api::TagError> {
+    api::parse_tag(tag)
 }
 
 #[derive(Args)]
@@ -197,8 +373,12 @@ pub enum ServerCommands {
     /// Update a host e.g. push a new `store_path` TODO: batch update
     Update {
         /// Name of the host
-        #[arg(long)]
-        host: String,
+        #[arg(long, required_unless_present = "tag")]
+        host: Option<String>,
+
+        /// Update all hosts matching the tags e.g. `role=web`
+        #[arg(long, conflicts_with = "host")]
+        tag: Option<api::TagSelector>,
 
         /// The new store path
         #[arg(long)]
@@ -235,15 +415,52 @@ pub enum ServerCommands {
         /// Public key to add
         #[arg(index = 1)]
         key: PathBuf,
-        /// Should the key be added as admin or as build
+        /// Role of the key
         #[arg(value_enum, index = 2)]
-        admin: AuthLevel,
+        role: AuthLevel,
+        /// Who or what the key belongs to e.g. `alice` or `ci-github`
+        #[arg(long, default_value = "")]
+        label: String,
+        /// Reject the key after this time e.g. `2026-12-31T00:00Z`
+        #[arg(long)]
+        expires: Option<jiff::Timestamp>,
+        /// Restrict the key to hosts matching the name or glob e.g. `web-*`. Can be repeated
+        #[arg(long)]
+        scope_host: Vec<String>,
+        /// Restrict the key to hosts matching the tags e.g. `team=web`. Can be repeated
+        #[arg(long)]
+        scope_tag: Vec<api::TagSelector>,
     },
+    /// List the admin and build keys with their last use
+    ListKeys,
     /// Remove a key from the server (can also used to remove hosts)
     RemoveKey {
-        /// Public key to remove
+        /// Public key file, label or fingerprint of the key to remove
         #[arg(index = 1)]
-        key: PathBuf,
+        key: String,
+    },
+    /// Mint a token hosts can enroll with instead of a verification code
+    CreateToken {
+        /// Name of the enrolled host. Without it hosts are named after their system hostname
+        #[arg(long)]
+        hostname: Option<String>,
+        /// Tag of the enrolled hosts in the form `key=value`. Can be repeated
+        #[arg(long = "tag", value_parser = parse_tag)]
+        tags: Vec<(String, String)>,
+        /// Hosts which can enroll with the token
+        #[arg(long, default_value = "1")]
+        uses: u32,
+        /// Reject the token after this time e.g. `2026-12-31T00:00Z`
+        #[arg(long)]
+        expires: jiff::Timestamp,
+    },
+    /// List the enrollment tokens which did not expire yet
+    ListTokens,
+    /// Revoke an enrollment token
+    RevokeToken {
+        /// ID of the token
+        #[arg(index = 1)]
+        id: uuid::Uuid,
     },
 }
 
@@ -253,4 +470,8 @@ pub enum AuthLevel {
     Admin,
     /// New key for build pipelines
     Build,
+    /// New read-only key e.g. for a helpdesk
+    Viewer,
+    /// New key which can detach, approve and manage rollouts but not keys
+    Operator,
 }
@@ -0,0 +1,38 @@
+//! Information about the git repository of a flake
+
+use std::{path::Path, process::Command};
+
+use rootcause::{Report, prelude::ResultExt as _};
+
+/// The commit checked out in the flake. `None` if the flake is not a git repository
+pub fn head_commit(flake_path: &Path) -> Result<Option<api::Commit>, Report> {
+    let log = Command::new("git")
+        .arg("-C")
+        .arg(flake_path)
+        .args(["log", "-1", "--format=%H%n%an <%ae>%n%s"])
+        .output()
+        .context("Could not run `git log`")?;
+    if !log.status.success() {
+        return Ok(None);
+    }
+
+    let log = String::from_utf8_lossy(&log.stdout);
+    let mut lines = log.lines();
+    let (Some(rev), Some(author)) = (lines.next(), lines.next()) else {
+        return Ok(None);
+    };
+
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(flake_path)
+        .args(["status", "--porcelain"])
+        .output()
+        .context("Could not run `git status`")?;
+
+    Ok(Some(api::Commit {
+        rev: rev.to_owned(),
+        subject: lines.next().unwrap_or_default().to_owned(),
+        author: author.to_owned(),
+        dirty: !status.stdout.is_empty(),
+    }))
+}
@@ -0,0 +1,97 @@
+//! Health checks run after switching to a new version.
+//!
+//! The checks are retried until they pass or the timeout is reached. Only units which failed
+//! after the switch count - units which were already broken before do not block an update.
+
+use std::{
+    collections::BTreeSet,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use httpsig_hyper::prelude::SecretKey;
+use log::{info, warn};
+use rootcause::{Report, prelude::ResultExt as _};
+use tokio::time;
+use yeet::server;
+
+use crate::cli_args::AgentConfig;
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Units currently in the failed state
+pub fn failed_units() -> Result<BTreeSet<String>, Report> {
+    let output = Command::new("systemctl")
+        .args(["list-units", "--state=failed", "--plain", "--no-legend", "--no-pager"])
+        .stdout(Stdio::piped())
+        .output()
+        .context("Could not run `systemctl list-units`")?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Run all configured checks until they pass or the timeout is reached.
+/// Returns a description of every check which still fails
+pub async fn check(
+    config: &AgentConfig,
+    key: &SecretKey,
+    failed_before: &BTreeSet<String>,
+) -> Vec<String> {
+    let deadline = Instant::now() + Duration::from_secs(config.health.timeout);
+
+    loop {
+        let failures = run_checks(config, key, failed_before).await;
+        if failures.is_empty() {
+            info!("All health checks passed");
+            return failures;
+        }
+        if Instant::now() >= deadline {
+            return failures;
+        }
+        warn!("Health checks failing - retrying:\n{}", failures.join("\n"));
+        time::sleep(RETRY_DELAY).await;
+    }
+}
+
+async fn run_checks(
+    config: &AgentConfig,
+    key: &SecretKey,
+    failed_before: &BTreeSet<String>,
+) -> Vec<String> {
+    let mut failures = Vec::new();
+
+    if config.health.failed_units {
+        match failed_units() {
+            Ok(failed) => failures.extend(
+                failed
+                    .difference(failed_before)
+                    .map(|unit| format!("Unit {unit} failed")),
+            ),
+            Err(err) => failures.push(format!("Could not list failed units: {err}")),
+        }
+    }
+
+    if config.health.server
+        && let Err(err) = server::system::is_host_verified(&config.server, key).await
+    {
+        failures.push(format!("Server {} not reachable: {err}", config.server));
+    }
+
+    for command in &config.health.commands {
+        match Command::new("sh").arg("-c").arg(command).output() {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => failures.push(format!(
+                "`{command}` failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+            Err(err) => failures.push(format!("Could not run `{command}`: {err}")),
+        }
+    }
+
+    failures
+}
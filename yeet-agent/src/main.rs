@@ -10,10 +10,12 @@ use figment::{
 use rootcause::{Report, hooks::Hooks};
 use yeet::nix::{self};
 
-use crate::cli_args::{AgentConfig, Commands, Config, HostArgs, Yeet};
+use crate::cli_args::{AgentConfig, Commands, Config, HostArgs, RolloutArgs, Yeet};
 
 mod agent;
 mod cli_args;
+mod git;
+mod health;
 mod section;
 mod server_cli;
 mod sig {
@@ -21,10 +23,12 @@ mod sig {
 }
 mod cli {
     pub mod approve;
+    pub mod audit;
     pub mod detach;
     pub mod host;
     pub mod hosts;
     pub mod publish;
+    pub mod rollout;
 }
 mod notification;
 mod polkit;
@@ -76,28 +80,66 @@ async fn main() -> Result<(), Report> {
             path,
         } => cli::detach::detach(version, force, path, darwin).await?,
         Commands::Attach => cli::detach::attach().await?,
-        Commands::Approve { name, code, facter } => {
-            cli::approve::approve(&config, facter, code, name).await?
+        Commands::Approve {
+            name,
+            code,
+            facter,
+            path,
+            darwin,
+        } => cli::approve::approve(&config, facter, code, name, path, darwin).await?,
+        Commands::Reject { code, blocklist } => {
+            cli::approve::reject(&config, code, blocklist).await?
         }
         Commands::Host(HostArgs { command }) => match command {
             cli_args::HostCommands::Rename { name, new } => {
                 cli::host::rename(&config, name, new).await?
             }
             cli_args::HostCommands::Remove { name } => cli::host::remove(&config, name).await?,
+            cli_args::HostCommands::Retry { name } => cli::host::retry(&config, name).await?,
+            cli_args::HostCommands::Placeholder {
+                name,
+                tags,
+                remove,
+                list,
+            } => cli::host::placeholder(&config, name, tags, remove, list).await?,
+            cli_args::HostCommands::Tag { name, tags, remove } => {
+                cli::host::tag(&config, name, tags, remove).await?
+            }
+            cli_args::HostCommands::Maintenance {
+                name,
+                tag,
+                window,
+                list,
+            } => cli::host::maintenance(&config, name, tag, window, list).await?,
         },
         Commands::Hosts { full } => cli::hosts::hosts(&config, full).await?,
-        Commands::Notify => notification::notify()?,
+        Commands::Audit {
+            host,
+            key,
+            since,
+            until,
+        } => cli::audit::audit(&config, host, key, since, until).await?,
+        Commands::Notify { commit } => notification::notify(commit)?,
         Commands::Agent {
             server,
             sleep,
             facter,
             key,
+            ca_bundle,
+            enrollment_token_file,
+            health,
         } => {
+            if let Some(ca_bundle) = &ca_bundle {
+                yeet::server::trust_ca_bundle(ca_bundle)?;
+            }
             let config = AgentConfig {
                 server,
                 sleep,
                 facter,
                 key,
+                ca_bundle,
+                enrollment_token_file,
+                health,
             };
             agent::agent(&config, sleep, facter).await?;
         }
@@ -105,13 +147,26 @@ async fn main() -> Result<(), Report> {
         Commands::Publish {
             path,
             host,
+            tag,
+            waves,
+            soak,
             darwin,
             netrc,
             variant,
         } => {
-            cli::publish::publish(&config, path, host, netrc, variant, darwin).await?;
+            let rollout = (!waves.is_empty()).then_some(api::RolloutPlan { waves, soak });
+            cli::publish::publish(&config, path, host, tag, rollout, netrc, variant, darwin)
+                .await?;
         }
         Commands::Server(args) => server_cli::handle_server_commands(args, &config).await?,
+        Commands::Rollout(RolloutArgs { command }) => match command {
+            cli_args::RolloutCommands::Status { all } => cli::rollout::status(&config, all).await?,
+            cli_args::RolloutCommands::Pause { id } => cli::rollout::pause(&config, id).await?,
+            cli_args::RolloutCommands::Resume { id } => cli::rollout::resume(&config, id).await?,
+            cli_args::RolloutCommands::Rollback { id, host } => {
+                cli::rollout::rollback(&config, id, host).await?;
+            }
+        },
     }
     Ok(())
 }
@@ -4,18 +4,22 @@ use rootcause::Report;
 use tokio::process::Command;
 use yeet::nix;
 
-pub fn notify() -> Result<(), Report> {
-    let variant = nix::nixos_variant_name()?;
+/// Show the update notification. Uses the commit if the version was published with one
+pub fn notify(commit: Option<String>) -> Result<(), Report> {
+    let version = match commit {
+        Some(commit) => commit,
+        None => nix::nixos_variant_name()?,
+    };
 
     notify_rust::Notification::new()
         .summary("System Update")
-        .body(&format!("System has been updated to `{variant}`"))
+        .body(&format!("System has been updated to `{version}`"))
         .appname("Yeet")
         .show()?;
     Ok(())
 }
 
-pub fn notify_all() -> Result<(), Report> {
+pub fn notify_all(commit: Option<&api::Commit>) -> Result<(), Report> {
     let user_dirs = {
         let dirs = fs::read_dir("/run/user")?;
         dirs.flatten()
@@ -28,8 +32,12 @@ pub fn notify_all() -> Result<(), Report> {
     for user in user_dirs {
         let dbus_address = format!("unix:path=/run/user/{user}/bus");
         let current_exe = std::env::current_exe().unwrap_or_else(|_| "yeet".into());
-        let _ = Command::new(current_exe)
-            .arg("notify")
+        let mut command = Command::new(current_exe);
+        command.arg("notify");
+        if let Some(commit) = commit {
+            command.arg("--commit").arg(commit.to_string());
+        }
+        let _ = command
             .uid(user)
             .env("DBUS_SESSION_BUS_ADDRESS", &dbus_address)
             // .env("DISPLAY", ":0")
@@ -16,15 +16,32 @@ impl ColoredDisplay<&str> for api::ProvisionState {
 
 impl DisplaySectionItem for api::Host {
     fn as_section_item(&self) -> (String, String) {
-        let commit_sha = self
-            .latest_store_path()
-            .rfind('.')
-            .map(|i| i + 1)
-            .unwrap_or(0);
+        // Without a published commit fall back to the revision nixos puts in the store path
+        let version = self.latest_version().commit.as_ref().map_or_else(
+            || {
+                let commit_sha = self
+                    .latest_store_path()
+                    .rfind('.')
+                    .map(|i| i + 1)
+                    .unwrap_or(0);
+                self.latest_store_path()[commit_sha..].to_owned()
+            },
+            ToString::to_string,
+        );
 
         let up_to_date = if let api::ProvisionState::Provisioned(version) = &self.provision_state {
             if &version.store_path == self.latest_store_path() {
                 style("Up to date ").green()
+            } else if self.failed_version.as_ref() == Some(&version.store_path) {
+                style("Failed     ").red()
+            } else if let Some(until) = &self.update_pending_until {
+                style(format!(
+                    "Update pending until {} ",
+                    until
+                        .with_time_zone(TimeZone::system())
+                        .strftime("%a %F %H:%M")
+                ))
+                .yellow()
             } else {
                 style("Outdated   ").red()
             }
@@ -38,7 +55,7 @@ impl DisplaySectionItem for api::Host {
             format!(
                 "{} ({}) {up_to_date}{}",
                 self.provision_state.colored_display(),
-                self.latest_store_path()[commit_sha..].to_owned(),
+                version,
                 display::time_diff(
                     &self.last_ping.with_time_zone(TimeZone::system()),
                     jiff::Unit::Second,
@@ -73,10 +90,17 @@ impl DisplaySection for api::Host {
             self.latest_store_path().to_string(),
         ));
 
+        if let Some(commit) = &self.latest_version().commit {
+            items.push(("Commit".to_string(), commit.to_string()));
+        }
+
         if let api::ProvisionState::Provisioned(ref remote_store) = self.provision_state
             && remote_store.store_path != *self.latest_store_path()
         {
             items.push(("Next version".to_string(), remote_store.store_path.clone()));
+            if let Some(commit) = &remote_store.commit {
+                items.push(("Next commit".to_string(), commit.to_string()));
+            }
         }
 
         {
@@ -89,6 +113,75 @@ impl DisplaySection for api::Host {
             items.push(("Last seen".to_string(), last_seen.to_string()));
         }
 
+        if let Some((report, time)) = &self.last_deployment {
+            let outcome = match report.outcome {
+                api::DeploymentOutcome::DownloadFailed => style("Download failed").red(),
+                api::DeploymentOutcome::ActivationFailed => style("Activation failed").red(),
+                api::DeploymentOutcome::HealthCheckFailed => style("Health check failed").red(),
+                api::DeploymentOutcome::Switched => style("Switched").green(),
+            };
+            let mut exit_code = report
+                .exit_code
+                .map_or("no exit code".to_owned(), |code| format!("exit code {code}"));
+            if report.rolled_back {
+                exit_code.push_str(", rolled back");
+            }
+            items.push((
+                "Last deployment".to_string(),
+                format!(
+                    "{} ({exit_code}) {}",
+                    outcome.bold(),
+                    display::time_diff(
+                        &time.with_time_zone(TimeZone::system()),
+                        jiff::Unit::Second,
+                        30_f64,
+                        jiff::Unit::Second
+                    )
+                ),
+            ));
+            if !report.stderr_tail.is_empty() {
+                items.push(("Error".to_string(), report.stderr_tail.clone()));
+            }
+        }
+
+        if self.failed_version.is_some() {
+            items.push((
+                "Held back".to_string(),
+                style("Failed version is not retried - see `yeet host retry`")
+                    .red()
+                    .to_string(),
+            ));
+        }
+
+        if let Some(until) = &self.update_pending_until {
+            items.push((
+                "Update pending until".to_string(),
+                style(
+                    until
+                        .with_time_zone(TimeZone::system())
+                        .strftime("%a %F %H:%M"),
+                )
+                .yellow()
+                .to_string(),
+            ));
+        }
+
+        if !self.maintenance_windows.is_empty() {
+            let windows: Vec<_> = self
+                .maintenance_windows
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            items.push(("Maintenance".to_string(), windows.join("\n")));
+        }
+
+        if !self.tags.is_empty() {
+            items.push((
+                "Tags".to_string(),
+                api::TagSelector(self.tags.clone()).to_string(),
+            ));
+        }
+
         if let Some(detach) = self.detach_allowed {
             items.push((
                 "Detach allowed".to_string(),
@@ -101,3 +194,211 @@ impl DisplaySection for api::Host {
         (style(&self.name).underlined().to_string(), items)
     }
 }
+
+impl DisplaySectionItem for api::KeyInfo {
+    fn as_section_item(&self) -> (String, String) {
+        let fingerprint = api::hash_hex(self.key.as_bytes());
+        let name = if self.label.is_empty() {
+            fingerprint.clone()
+        } else {
+            self.label.clone()
+        };
+
+        let local = |time: &jiff::Zoned| {
+            time.with_time_zone(TimeZone::system())
+                .strftime("%F %T")
+                .to_string()
+        };
+        let last_used = self.last_used.as_ref().map_or_else(
+            || style("never used".to_owned()).dim().to_string(),
+            |last_used| format!("used {}", local(last_used)),
+        );
+        let expires = match &self.expires {
+            Some(expires) if self.is_expired(&jiff::Zoned::now()) => {
+                style(format!("expired {}", local(expires)))
+                    .red()
+                    .to_string()
+            }
+            Some(expires) => format!("expires {}", local(expires)),
+            None => String::new(),
+        };
+        let scope = self
+            .scope
+            .as_ref()
+            .map(|scope| format!("\nscope {scope}"))
+            .unwrap_or_default();
+
+        (
+            name,
+            format!(
+                "{:?} {fingerprint}\ncreated {}, {last_used}{scope}\n{expires}",
+                self.level,
+                local(&self.created)
+            )
+            .trim_end()
+            .to_owned(),
+        )
+    }
+}
+
+impl DisplaySectionItem for api::enroll::EnrollmentToken {
+    fn as_section_item(&self) -> (String, String) {
+        let hostname = self
+            .hostname
+            .clone()
+            .unwrap_or_else(|| "system hostname".to_owned());
+        let tags = if self.tags.is_empty() {
+            String::new()
+        } else {
+            format!(" {}", api::TagSelector(self.tags.clone()))
+        };
+        (
+            self.id.to_string(),
+            format!(
+                "{hostname}{tags}\n{} uses left, expires {}",
+                self.uses_left,
+                self.expires
+                    .with_time_zone(TimeZone::system())
+                    .strftime("%F %T")
+            ),
+        )
+    }
+}
+
+impl DisplaySectionItem for api::PlaceholderHost {
+    fn as_section_item(&self) -> (String, String) {
+        let version = match &self.provision_state {
+            api::ProvisionState::Provisioned(version) => version.store_path.clone(),
+            _ => "no version yet".to_owned(),
+        };
+        let tags = if self.tags.is_empty() {
+            String::new()
+        } else {
+            format!("\n{}", api::TagSelector(self.tags.clone()))
+        };
+        (self.name.clone(), format!("{version}{tags}"))
+    }
+}
+
+impl DisplaySectionItem for api::AuditEntry {
+    fn as_section_item(&self) -> (String, String) {
+        let who = self
+            .key_name
+            .clone()
+            .unwrap_or_else(|| api::hash_hex(self.key.as_bytes()));
+
+        let action = match &self.action {
+            api::AuditAction::Update { hosts, commit, .. } => {
+                let mut hosts: Vec<_> = hosts.keys().cloned().collect();
+                hosts.sort();
+                match commit {
+                    Some(commit) => format!("Published {} at {commit}", hosts.join(", ")),
+                    None => format!("Published {}", hosts.join(", ")),
+                }
+            }
+            api::AuditAction::AddKey {
+                key, level, label, ..
+            } => {
+                let key = api::hash_hex(key.as_bytes());
+                if label.is_empty() {
+                    format!("Added {level:?} key {key}")
+                } else {
+                    format!("Added {level:?} key {key} ({label})")
+                }
+            }
+            api::AuditAction::RemoveKey { key } => {
+                format!("Removed key {}", api::hash_hex(key.as_bytes()))
+            }
+            api::AuditAction::RemoveHost { hostname } => format!("Removed host {hostname}"),
+            api::AuditAction::CreatePlaceholder(api::PlaceholderRequest { hostname, .. }) => {
+                format!("Pre-provisioned {hostname}")
+            }
+            api::AuditAction::RemovePlaceholder { hostname } => {
+                format!("Removed placeholder {hostname}")
+            }
+            api::AuditAction::RenameHost {
+                current_name,
+                new_name,
+            } => format!("Renamed {current_name} to {new_name}"),
+            api::AuditAction::Detach(detach) => match detach {
+                api::DetachAction::DetachSelf => "Detached itself".to_owned(),
+                api::DetachAction::AttachSelf => "Attached itself".to_owned(),
+                api::DetachAction::DetachHost(hostname) => format!("Detached {hostname}"),
+                api::DetachAction::AttachHost(hostname) => format!("Attached {hostname}"),
+            },
+            api::AuditAction::SetDetachPermission(permission) => match permission {
+                api::SetDetachPermission::Global(allowed) => {
+                    format!("Set global detach permission to {allowed}")
+                }
+                api::SetDetachPermission::PerHost(hosts) => format!(
+                    "Set detach permission {}",
+                    hosts
+                        .iter()
+                        .map(|(hostname, allowed)| format!("{hostname}={allowed}"))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            },
+            api::AuditAction::AcceptVerification { code, hostname } => {
+                format!("Approved {hostname} with code {code}")
+            }
+            api::AuditAction::SetTags(api::HostTagsRequest { hostname, .. }) => {
+                format!("Set tags of {hostname}")
+            }
+            api::AuditAction::RejectVerification {
+                code,
+                key,
+                blocklist,
+            } => {
+                let key = api::hash_hex(key.as_bytes());
+                if *blocklist {
+                    format!("Rejected code {code} and blocked key {key}")
+                } else {
+                    format!("Rejected code {code} of key {key}")
+                }
+            }
+            api::AuditAction::CreateEnrollmentToken {
+                id, hostname, uses, ..
+            } => match hostname {
+                Some(hostname) => format!("Created enrollment token {id} for {hostname}"),
+                None => format!("Created enrollment token {id} for {uses} hosts"),
+            },
+            api::AuditAction::RevokeEnrollmentToken { id } => {
+                format!("Revoked enrollment token {id}")
+            }
+            api::AuditAction::Enroll { token, hostname } => {
+                format!("Enrolled {hostname} with token {token}")
+            }
+            api::AuditAction::SetMaintenance(api::maintenance::MaintenanceRequest {
+                target,
+                windows,
+            }) => {
+                let target = match target {
+                    api::maintenance::MaintenanceTarget::Host(hostname) => hostname.clone(),
+                    api::maintenance::MaintenanceTarget::Group(selector) => selector.to_string(),
+                };
+                let windows: Vec<_> = windows.iter().map(ToString::to_string).collect();
+                format!("Set maintenance windows of {target} to [{}]", windows.join(", "))
+            }
+            api::AuditAction::RetryDeployment(api::RetryRequest { hostname }) => {
+                format!("Retried the update of {hostname}")
+            }
+            api::AuditAction::PauseRollout { id } => format!("Paused rollout {id}"),
+            api::AuditAction::ResumeRollout { id } => format!("Resumed rollout {id}"),
+            api::AuditAction::Rollback { target, hosts } => match target {
+                api::RollbackTarget::Rollout(id) => {
+                    format!("Rolled back rollout {id} ({})", hosts.join(", "))
+                }
+                api::RollbackTarget::Hosts(_) => format!("Rolled back {}", hosts.join(", ")),
+            },
+        };
+
+        (
+            self.time
+                .with_time_zone(TimeZone::system())
+                .strftime("%F %T")
+                .to_string(),
+            format!("{} {action}", style(who).bold()),
+        )
+    }
+}
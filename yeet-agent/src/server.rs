@@ -1,10 +1,14 @@
-use std::sync::LazyLock;
+use std::{
+    fs,
+    path::Path,
+    sync::{LazyLock, OnceLock},
+};
 
 use api::httpsig::ReqwestSig as _;
 use http::StatusCode;
 use httpsig_hyper::prelude::*;
 use reqwest::{Client, Response};
-use rootcause::{Report, report};
+use rootcause::{Report, prelude::ResultExt as _, report};
 use serde::de::DeserializeOwned;
 use url::Url;
 
@@ -17,8 +21,30 @@ static COMPONENTS: LazyLock<Vec<message_component::HttpMessageComponentId>> = La
         .expect("Could not create HTTP Signature components")
 });
 
+/// Client used for all requests to the server - see [`trust_ca_bundle`]
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+fn client() -> Client {
+    CLIENT.get().cloned().unwrap_or_default()
+}
+
+/// Trust the CA certificates of the PEM bundle in addition to the system ones.
+/// Has to be called before the first request
+pub fn trust_ca_bundle(path: &Path) -> Result<(), Report> {
+    let bundle = fs::read(path)
+        .context(format!("Could not read CA bundle {}", path.display()))?;
+    let client = reqwest::Certificate::from_pem_bundle(&bundle)?
+        .into_iter()
+        .fold(Client::builder(), reqwest::ClientBuilder::add_root_certificate)
+        .build()?;
+    if CLIENT.set(client).is_err() {
+        return Err(report!("CA bundle is already set"));
+    }
+    Ok(())
+}
+
 pub async fn status<K: SigningKey + Sync>(url: &Url, key: &K) -> Result<Vec<api::Host>, Report> {
-    Client::new()
+    client()
         .get(url.join("/status")?)
         .sign(&sig_param(key)?, key)
         .await?
@@ -28,22 +54,109 @@ pub async fn status<K: SigningKey + Sync>(url: &Url, key: &K) -> Result<Vec<api:
         .await
 }
 
+pub async fn audit<K: SigningKey + Sync>(
+    url: &Url,
+    key: &K,
+    query: &api::AuditQuery,
+) -> Result<Vec<api::AuditEntry>, Report> {
+    client()
+        .post(url.join("/audit")?)
+        .json(query)
+        .sign(&sig_param(key)?, key)
+        .await?
+        .send()
+        .await?
+        .error_for_json()
+        .await
+}
+
+pub mod rollout {
+    use api::httpsig::ReqwestSig as _;
+    use http::StatusCode;
+    use httpsig_hyper::prelude::*;
+    use rootcause::Report;
+    use url::Url;
+
+    use crate::server::{ErrorForJson as _, client, sig_param};
+
+    pub async fn list<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+    ) -> Result<Vec<api::Rollout>, Report> {
+        client()
+            .get(url.join("/rollout")?)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_json()
+            .await
+    }
+
+    pub async fn pause<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        id: uuid::Uuid,
+    ) -> Result<StatusCode, Report> {
+        client()
+            .post(url.join("/rollout/pause")?)
+            .json(&api::RolloutRequest { id })
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_code()
+            .await
+    }
+
+    pub async fn resume<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        id: uuid::Uuid,
+    ) -> Result<StatusCode, Report> {
+        client()
+            .post(url.join("/rollout/resume")?)
+            .json(&api::RolloutRequest { id })
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_code()
+            .await
+    }
+
+    pub async fn rollback<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        target: &api::RollbackTarget,
+    ) -> Result<Vec<String>, Report> {
+        client()
+            .post(url.join("/rollout/rollback")?)
+            .json(target)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_json()
+            .await
+    }
+}
+
 pub mod key {
     use api::httpsig::ReqwestSig as _;
     use http::StatusCode;
     use httpsig_hyper::prelude::*;
-    use reqwest::Client;
     use rootcause::Report;
     use url::Url;
 
-    use crate::server::{ErrorForJson as _, sig_param};
+    use crate::server::{ErrorForJson as _, client, sig_param};
 
     pub async fn add_key<K: SigningKey + Sync>(
         url: &Url,
         key: &K,
         add_key: &api::AddKey,
     ) -> Result<StatusCode, Report> {
-        Client::new()
+        client()
             .post(url.join("/key/add")?)
             .json(add_key)
             .sign(&sig_param(key)?, key)
@@ -54,12 +167,26 @@ pub mod key {
             .await
     }
 
+    pub async fn list<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+    ) -> Result<Vec<api::KeyInfo>, Report> {
+        client()
+            .get(url.join("/key/list")?)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_json()
+            .await
+    }
+
     pub async fn remove_key<K: SigningKey + Sync>(
         url: &Url,
         key: &K,
         remove_key: &ed25519_dalek::VerifyingKey,
     ) -> Result<StatusCode, Report> {
-        Client::new()
+        client()
             .post(url.join("/key/remove")?)
             .json(remove_key)
             .sign(&sig_param(key)?, key)
@@ -75,18 +202,17 @@ pub mod host {
     use api::httpsig::ReqwestSig as _;
     use http::StatusCode;
     use httpsig_hyper::prelude::*;
-    use reqwest::Client;
     use rootcause::Report;
     use url::Url;
 
-    use crate::server::{ErrorForJson as _, sig_param};
+    use crate::server::{ErrorForJson as _, client, sig_param};
 
     pub async fn remove_host<K: SigningKey + Sync>(
         url: &Url,
         key: &K,
         request: &api::HostRemoveRequest,
     ) -> Result<api::Host, Report> {
-        Client::new()
+        client()
             .post(url.join("/host/remove")?)
             .json(request)
             .sign(&sig_param(key)?, key)
@@ -102,7 +228,7 @@ pub mod host {
         key: &K,
         request: &api::HostRenameRequest,
     ) -> Result<StatusCode, Report> {
-        Client::new()
+        client()
             .post(url.join("/host/rename")?)
             .json(request)
             .sign(&sig_param(key)?, key)
@@ -112,24 +238,147 @@ pub mod host {
             .error_for_code()
             .await
     }
+
+    pub async fn set_tags<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        request: &api::HostTagsRequest,
+    ) -> Result<StatusCode, Report> {
+        client()
+            .post(url.join("/host/tags")?)
+            .json(request)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_code()
+            .await
+    }
+
+    pub async fn create_placeholder<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        request: &api::PlaceholderRequest,
+    ) -> Result<StatusCode, Report> {
+        client()
+            .post(url.join("/host/placeholder")?)
+            .json(request)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_code()
+            .await
+    }
+
+    pub async fn list_placeholders<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+    ) -> Result<Vec<api::PlaceholderHost>, Report> {
+        client()
+            .get(url.join("/host/placeholder")?)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_json()
+            .await
+    }
+
+    pub async fn remove_placeholder<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        request: &api::HostRemoveRequest,
+    ) -> Result<StatusCode, Report> {
+        client()
+            .post(url.join("/host/placeholder/remove")?)
+            .json(request)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_code()
+            .await
+    }
+
+    pub async fn select<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        selector: &api::TagSelector,
+    ) -> Result<Vec<String>, Report> {
+        client()
+            .post(url.join("/host/select")?)
+            .json(selector)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_json()
+            .await
+    }
+
+    pub async fn set_maintenance<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        request: &api::maintenance::MaintenanceRequest,
+    ) -> Result<StatusCode, Report> {
+        client()
+            .post(url.join("/host/maintenance")?)
+            .json(request)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_code()
+            .await
+    }
+
+    pub async fn retry<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        request: &api::RetryRequest,
+    ) -> Result<StatusCode, Report> {
+        client()
+            .post(url.join("/host/retry")?)
+            .json(request)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_code()
+            .await
+    }
+
+    pub async fn group_maintenance<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+    ) -> Result<Vec<api::maintenance::GroupMaintenance>, Report> {
+        client()
+            .get(url.join("/host/maintenance")?)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_json()
+            .await
+    }
 }
 pub mod system {
 
     use api::httpsig::ReqwestSig as _;
     use http::StatusCode;
     use httpsig_hyper::prelude::*;
-    use reqwest::Client;
     use rootcause::Report;
     use url::Url;
 
-    use crate::server::{ErrorForJson as _, sig_param};
+    use crate::server::{ErrorForJson as _, client, sig_param};
 
     pub async fn check<K: SigningKey + Sync>(
         url: &Url,
         key: &K,
         version: &api::VersionRequest,
     ) -> Result<api::AgentAction, Report> {
-        Client::new()
+        client()
             .post(url.join("/system/check")?)
             .json(version)
             .sign(&sig_param(key)?, key)
@@ -140,19 +389,55 @@ pub mod system {
             .await
     }
 
+    /// Long-poll variant of [`check`] which returns once there is something to do or the
+    /// server timed out the request. `None` if the server does not support long polling
+    pub async fn check_wait<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        version: &api::VersionRequest,
+    ) -> Result<Option<api::AgentAction>, Report> {
+        let response = client()
+            .post(url.join("/system/check/wait")?)
+            .json(version)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        response.error_for_json().await.map(Some)
+    }
+
+    pub async fn report<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        report: &api::DeploymentReport,
+    ) -> Result<StatusCode, Report> {
+        client()
+            .post(url.join("/system/report")?)
+            .json(report)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_code()
+            .await
+    }
+
     pub async fn update<K: SigningKey + Sync>(
         url: &Url,
         key: &K,
         host_update_request: &api::HostUpdateRequest,
-    ) -> Result<StatusCode, Report> {
-        Client::new()
+    ) -> Result<api::HostUpdateResponse, Report> {
+        client()
             .post(url.join("/system/update")?)
             .json(host_update_request)
             .sign(&sig_param(key)?, key)
             .await?
             .send()
             .await?
-            .error_for_code()
+            .error_for_json()
             .await
     }
 
@@ -160,7 +445,7 @@ pub mod system {
         url: &Url,
         key: &K,
     ) -> Result<StatusCode, Report> {
-        Ok(Client::new()
+        Ok(client()
             .get(url.join("/system/verify")?)
             .sign(&sig_param(key)?, key)
             .await?
@@ -173,7 +458,7 @@ pub mod system {
         url: &Url,
         attempt: &api::VerificationAttempt,
     ) -> Result<u32, Report> {
-        Client::new()
+        client()
             .post(url.join("/system/verify")?)
             .json(attempt)
             .send()
@@ -182,12 +467,23 @@ pub mod system {
             .await
     }
 
+    /// Enroll with a token instead of a verification code. Returns the name of the host
+    pub async fn enroll(url: &Url, enrollment: &api::enroll::Enrollment) -> Result<String, Report> {
+        client()
+            .post(url.join("/system/enroll")?)
+            .json(enrollment)
+            .send()
+            .await?
+            .error_for_json()
+            .await
+    }
+
     pub async fn verify_attempt<K: SigningKey + Sync>(
         url: &Url,
         key: &K,
         acceptance: &api::VerificationAcceptance,
     ) -> Result<api::VerificationArtifacts, Report> {
-        Client::new()
+        client()
             .post(url.join("/system/verify/accept")?)
             .json(acceptance)
             .sign(&sig_param(key)?, key)
@@ -198,12 +494,42 @@ pub mod system {
             .await
     }
 
+    pub async fn pending<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+    ) -> Result<Vec<api::PendingVerification>, Report> {
+        client()
+            .get(url.join("/system/verify/pending")?)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_json()
+            .await
+    }
+
+    pub async fn reject<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        rejection: &api::VerificationRejection,
+    ) -> Result<StatusCode, Report> {
+        client()
+            .post(url.join("/system/verify/reject")?)
+            .json(rejection)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_code()
+            .await
+    }
+
     pub async fn detach<K: SigningKey + Sync>(
         url: &Url,
         key: &K,
         detach: &api::DetachAction,
     ) -> Result<StatusCode, Report> {
-        Client::new()
+        client()
             .post(url.join("/system/detach")?)
             .json(detach)
             .sign(&sig_param(key)?, key)
@@ -218,7 +544,7 @@ pub mod system {
         url: &Url,
         key: &K,
     ) -> Result<bool, Report> {
-        Client::new()
+        client()
             .get(url.join("/system/detach/permission")?)
             .sign(&sig_param(key)?, key)
             .await?
@@ -229,22 +555,81 @@ pub mod system {
     }
 }
 
+pub mod enroll {
+    use api::{
+        enroll::{EnrollmentToken, TokenRequest},
+        httpsig::ReqwestSig as _,
+    };
+    use http::StatusCode;
+    use httpsig_hyper::prelude::*;
+    use rootcause::Report;
+    use url::Url;
+    use uuid::Uuid;
+
+    use crate::server::{ErrorForJson as _, client, sig_param};
+
+    pub async fn create_token<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        request: &TokenRequest,
+    ) -> Result<EnrollmentToken, Report> {
+        client()
+            .post(url.join("/enroll/token")?)
+            .json(request)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_json()
+            .await
+    }
+
+    pub async fn list_tokens<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+    ) -> Result<Vec<EnrollmentToken>, Report> {
+        client()
+            .get(url.join("/enroll/token")?)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_json()
+            .await
+    }
+
+    pub async fn revoke_token<K: SigningKey + Sync>(
+        url: &Url,
+        key: &K,
+        id: &Uuid,
+    ) -> Result<StatusCode, Report> {
+        client()
+            .post(url.join("/enroll/token/revoke")?)
+            .json(id)
+            .sign(&sig_param(key)?, key)
+            .await?
+            .send()
+            .await?
+            .error_for_code()
+            .await
+    }
+}
+
 pub mod detach {
     use api::httpsig::ReqwestSig as _;
     use http::StatusCode;
     use httpsig_hyper::prelude::*;
-    use reqwest::Client;
     use rootcause::Report;
     use url::Url;
 
-    use crate::server::{ErrorForJson as _, sig_param};
+    use crate::server::{ErrorForJson as _, client, sig_param};
 
     pub async fn set_detach_permission<K: SigningKey + Sync>(
         url: &Url,
         key: &K,
         permission: &api::SetDetachPermission,
     ) -> Result<StatusCode, Report> {
-        Client::new()
+        client()
             .post(url.join("/detach/permission")?)
             .json(permission)
             .sign(&sig_param(key)?, key)
@@ -259,7 +644,7 @@ pub mod detach {
         url: &Url,
         key: &K,
     ) -> Result<bool, Report> {
-        Client::new()
+        client()
             .get(url.join("/detach/permission")?)
             .sign(&sig_param(key)?, key)
             .await?
@@ -270,9 +655,16 @@ pub mod detach {
     }
 }
 
+/// Seconds a signature is valid. Same as the default skew yeetd allows so that clocks which are
+/// off are tolerated the same way
+const SIGNATURE_LIFETIME: u64 = 300;
+
+/// The nonce and expiry let the server reject replayed requests
 fn sig_param<K: SigningKey + Sync>(key: &K) -> Result<HttpSignatureParams, Report> {
     let mut signature_params = HttpSignatureParams::try_new(&COMPONENTS)?;
     signature_params.set_key_info(key);
+    signature_params.set_random_nonce();
+    signature_params.set_expires_with_duration(Some(SIGNATURE_LIFETIME));
     Ok(signature_params)
 }
 
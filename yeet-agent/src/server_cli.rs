@@ -1,11 +1,16 @@
-use std::{collections::HashMap, fs::read_to_string};
+use std::{collections::HashMap, fs::read_to_string, path::Path};
 
 use api::key::{get_secret_key, get_verify_key};
+use console::style;
 use log::info;
 use rootcause::{Report, prelude::ResultExt as _};
 use yeet::server;
 
-use crate::cli_args::{AuthLevel, Config, ServerArgs, ServerCommands};
+use crate::{
+    cli,
+    cli_args::{AuthLevel, Config, ServerArgs, ServerCommands},
+    section::{self, DisplaySectionItem as _},
+};
 
 pub async fn handle_server_commands(args: ServerArgs, config: &Config) -> Result<(), Report> {
     let url = &config
@@ -19,6 +24,7 @@ pub async fn handle_server_commands(args: ServerArgs, config: &Config) -> Result
     match args.command {
         ServerCommands::Update {
             host,
+            tag,
             store_path,
             public_key,
             substitutor,
@@ -32,17 +38,28 @@ pub async fn handle_server_commands(args: ServerArgs, config: &Config) -> Result
                 ),
                 None => None,
             };
-            server::system::update(
+            let key = get_secret_key(&httpsig_key)?;
+            // The server resolves the selector itself
+            let hosts = host
+                .into_iter()
+                .map(|host| (host, store_path.clone()))
+                .collect::<HashMap<_, _>>();
+            let response = server::system::update(
                 &url,
-                &get_secret_key(&httpsig_key)?,
+                &key,
                 &api::HostUpdateRequest {
-                    hosts: HashMap::from([(host, store_path)]),
+                    hosts,
                     public_key,
                     substitutor,
                     netrc,
+                    selector: tag,
+                    store_path: Some(store_path),
+                    rollout: None,
+                    commit: None,
                 },
             )
             .await?;
+            cli::publish::check_rejected(&response)?;
         }
         ServerCommands::VerifyStatus => {
             let status =
@@ -71,11 +88,19 @@ pub async fn handle_server_commands(args: ServerArgs, config: &Config) -> Result
             .await?;
             info!("{code}");
         }
-        ServerCommands::AddKey { key, admin } => {
-            let level = if admin == AuthLevel::Admin {
-                api::AuthLevel::Admin
-            } else {
-                api::AuthLevel::Build
+        ServerCommands::AddKey {
+            key,
+            role,
+            label,
+            expires,
+            scope_host,
+            scope_tag,
+        } => {
+            let level = match role {
+                AuthLevel::Admin => api::AuthLevel::Admin,
+                AuthLevel::Build => api::AuthLevel::Build,
+                AuthLevel::Viewer => api::AuthLevel::Viewer,
+                AuthLevel::Operator => api::AuthLevel::Operator,
             };
             let status = server::key::add_key(
                 &url,
@@ -83,20 +108,90 @@ pub async fn handle_server_commands(args: ServerArgs, config: &Config) -> Result
                 &api::AddKey {
                     key: get_verify_key(&key)?,
                     level,
+                    label,
+                    expires: expires.map(|expires| expires.to_zoned(jiff::tz::TimeZone::system())),
+                    scope: (!scope_host.is_empty() || !scope_tag.is_empty()).then_some(
+                        api::HostScope {
+                            hosts: scope_host,
+                            tags: scope_tag,
+                        },
+                    ),
                 },
             )
             .await?;
             info!("{status}");
         }
-        ServerCommands::RemoveKey { key } => {
-            let status = server::key::remove_key(
+        ServerCommands::ListKeys => {
+            let keys = server::key::list(&url, &get_secret_key(&httpsig_key)?).await?;
+            section::print_sections(&[(
+                style("Keys:").underlined().to_string(),
+                keys.iter().map(|key| key.as_section_item()).collect(),
+            )]);
+        }
+        ServerCommands::CreateToken {
+            hostname,
+            tags,
+            uses,
+            expires,
+        } => {
+            let token = server::enroll::create_token(
                 &url,
                 &get_secret_key(&httpsig_key)?,
-                &get_verify_key(&key)?,
+                &api::enroll::TokenRequest {
+                    hostname,
+                    tags: tags.into_iter().collect(),
+                    uses,
+                    expires: expires.to_zoned(jiff::tz::TimeZone::system()),
+                },
             )
             .await?;
+            info!("Token {}: {}", token.id, token.token);
+        }
+        ServerCommands::ListTokens => {
+            let tokens =
+                server::enroll::list_tokens(&url, &get_secret_key(&httpsig_key)?).await?;
+            section::print_sections(&[(
+                style("Enrollment tokens:").underlined().to_string(),
+                tokens.iter().map(|token| token.as_section_item()).collect(),
+            )]);
+        }
+        ServerCommands::RevokeToken { id } => {
+            let status =
+                server::enroll::revoke_token(&url, &get_secret_key(&httpsig_key)?, &id).await?;
+            info!("{status}");
+        }
+        ServerCommands::RemoveKey { key } => {
+            let secret_key = get_secret_key(&httpsig_key)?;
+            let key = if Path::new(&key).exists() {
+                get_verify_key(&key)?
+            } else {
+                find_key(&url, &secret_key, &key).await?
+            };
+            let status = server::key::remove_key(&url, &secret_key, &key).await?;
             info!("{status}");
         }
     }
     Ok(())
 }
+
+/// Resolve a key by its label or fingerprint. A label has to be unique
+async fn find_key<K: httpsig_hyper::prelude::SigningKey + Sync>(
+    url: &url::Url,
+    secret_key: &K,
+    name: &str,
+) -> Result<ed25519_dalek::VerifyingKey, Report> {
+    let keys = server::key::list(url, secret_key).await?;
+    let matches: Vec<_> = keys
+        .iter()
+        .filter(|key| key.label == name || api::hash_hex(key.key.as_bytes()) == name)
+        .collect();
+    match matches.as_slice() {
+        [key] => Ok(key.key),
+        [] => Err(rootcause::report!(
+            "`{name}` is neither a key file nor the label or fingerprint of a key"
+        )),
+        _ => Err(rootcause::report!(
+            "`{name}` is the label of multiple keys - use the fingerprint instead"
+        )),
+    }
+}
@@ -0,0 +1,53 @@
+//! Enrollment tokens let hosts enroll without an admin approving a verification code.
+//!
+//! An admin mints a token with [`TokenRequest`]. The agent sends it together with its key in an
+//! [`Enrollment`] and is registered right away under the hostname of the token. Tokens can be used
+//! a limited number of times and expire.
+
+use jiff::Zoned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{StorePath, Tags, VerificationArtifacts};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// Mint a new enrollment token
+pub struct TokenRequest {
+    /// Name of the enrolled host. Without it the host is named after its system hostname
+    pub hostname: Option<String>,
+    /// Tags of the enrolled hosts
+    pub tags: Tags,
+    /// Hosts which can enroll with the token. Has to be 1 if a hostname is set
+    pub uses: u32,
+    pub expires: Zoned,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EnrollmentToken {
+    pub id: Uuid,
+    /// The secret the agent has to send. Only returned when the token is minted
+    pub token: String,
+    pub hostname: Option<String>,
+    pub tags: Tags,
+    pub uses_left: u32,
+    pub created: Zoned,
+    pub expires: Zoned,
+}
+
+impl EnrollmentToken {
+    #[must_use]
+    pub fn is_expired(&self, now: &Zoned) -> bool {
+        self.expires <= *now
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq)]
+/// Sent by the agent instead of a [`VerificationAttempt`](crate::VerificationAttempt)
+pub struct Enrollment {
+    pub token: String,
+    pub key: ed25519_dalek::VerifyingKey,
+    pub store_path: StorePath,
+    /// System hostname of the agent. Used if the token has no hostname
+    pub hostname: Option<String>,
+    pub artifacts: VerificationArtifacts,
+}
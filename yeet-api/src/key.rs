@@ -31,11 +31,15 @@ pub enum KeyError {
 // - a public pkcs8 pem file
 // - a private pkcs8 pem file (derive)
 pub fn get_verify_key<P: AsRef<Path>>(path: P) -> Result<VerifyingKey, KeyError> {
-    let key = read_to_string(path)?;
-    verifying_from_private_ssh(&key)
-        .or_else(|_| verifying_from_pub_ssh(key.as_str()))
-        .or_else(|_| SigningKey::from_pkcs8_pem(key.as_str()).map(|k| k.verifying_key()))
-        .or_else(|_| VerifyingKey::from_public_key_pem(key.as_str()))
+    parse_verify_key(&read_to_string(path)?)
+}
+
+// Same as `get_verify_key` but with the key itself instead of a path
+pub fn parse_verify_key(key: &str) -> Result<VerifyingKey, KeyError> {
+    verifying_from_private_ssh(key)
+        .or_else(|_| verifying_from_pub_ssh(key))
+        .or_else(|_| SigningKey::from_pkcs8_pem(key).map(|k| k.verifying_key()))
+        .or_else(|_| VerifyingKey::from_public_key_pem(key))
         .map_err(|_err| KeyError::KeyNotSupported)
 }
 
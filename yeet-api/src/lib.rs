@@ -1,17 +1,29 @@
 //! API for yeet
 
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    str::FromStr,
+};
 
 use ed25519_dalek::VerifyingKey;
-use jiff::Zoned;
+use jiff::{SignedDuration, Timestamp, Zoned};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+use wildmatch::WildMatch;
 
+pub mod enroll;
 pub mod httpsig;
 pub mod key;
+pub mod maintenance;
 pub mod status;
+pub mod webhook;
 
 pub type StorePath = String;
 pub type NETRC = String;
+/// `key=value` labels of a host e.g. `role=web` or `site=zurich`
+pub type Tags = BTreeMap<String, String>;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 /// Represents a Host Update Request
@@ -29,6 +41,225 @@ pub struct HostUpdateRequest {
     pub substitutor: String,
     /// netrc File to use when downloading from the cache. Useful when using private caches
     pub netrc: Option<String>,
+    /// Also update every host matching the selector with `store_path`
+    #[serde(default)]
+    pub selector: Option<TagSelector>,
+    /// Version of the hosts matching `selector` which are not named in `hosts`
+    #[serde(default)]
+    pub store_path: Option<StorePath>,
+    /// Ship the update in waves instead of to all hosts at once
+    #[serde(default)]
+    pub rollout: Option<RolloutPlan>,
+    /// Commit of the flake the hosts were built from
+    #[serde(default)]
+    pub commit: Option<Commit>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+/// Answer to a [`HostUpdateRequest`]. The update is applied to every host which is not rejected
+pub struct HostUpdateResponse {
+    /// Hosts which were not updated with the reason
+    pub rejected: BTreeMap<String, UpdateRejection>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+/// Why a host of a [`HostUpdateRequest`] was not updated
+pub enum UpdateRejection {
+    /// The host is outside the scope of the build key
+    OutOfScope,
+    /// The host did not enroll and has no placeholder
+    UnknownHost,
+}
+
+impl Display for UpdateRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpdateRejection::OutOfScope => f.write_str("outside the scope of the key"),
+            UpdateRejection::UnknownHost => f.write_str("no such host or placeholder"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// Git commit of the flake a version was built from
+pub struct Commit {
+    pub rev: String,
+    /// First line of the commit message
+    pub subject: String,
+    pub author: String,
+    /// The working tree had uncommitted changes when the version was built
+    pub dirty: bool,
+}
+
+impl Display for Commit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let short = self.rev.get(..7).unwrap_or(&self.rev);
+        let dirty = if self.dirty { "-dirty" } else { "" };
+        write!(f, "{short}{dirty} {} ({})", self.subject, self.author)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// How a rollout ships a version to its hosts
+pub struct RolloutPlan {
+    /// Cumulative percentage of hosts in each wave e.g. `[5, 25, 100]`.
+    /// Has to be increasing and end with 100
+    pub waves: Vec<u8>,
+    /// How long the hosts of a wave have to run the new version before the next wave starts
+    pub soak: SignedDuration,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RolloutState {
+    Running,
+    /// No further waves are started until the rollout is resumed
+    Paused,
+    Completed,
+    /// Hosts of the rollout were reverted to their previous version
+    RolledBack,
+}
+
+impl RolloutState {
+    /// Running and paused rollouts still have hosts waiting for a later wave
+    #[must_use]
+    pub fn is_active(self) -> bool {
+        match self {
+            RolloutState::Running | RolloutState::Paused => true,
+            RolloutState::Completed | RolloutState::RolledBack => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Pause or resume a rollout
+pub struct RolloutRequest {
+    pub id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// What to revert to the previous version
+pub enum RollbackTarget {
+    /// Every host the rollout already shipped to. Stops the rollout
+    Rollout(Uuid),
+    /// Individual hosts e.g. after an update without a rollout
+    Hosts(Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// A version shipped in waves. Which wave a host is in is decided by [`rollout_bucket`]
+pub struct Rollout {
+    pub id: Uuid,
+    pub plan: RolloutPlan,
+    /// Version each host of the rollout gets
+    pub hosts: HashMap<String, RemoteStorePath>,
+    /// Index into `plan.waves` of the wave currently shipped
+    pub wave: usize,
+    pub wave_started: Zoned,
+    pub created: Zoned,
+    pub state: RolloutState,
+}
+
+/// Bucket (0-99) of a host in a rollout. A host is part of a wave if its bucket is below the
+/// percentage of the wave. Buckets only depend on the hostname so the same hosts are always
+/// the canaries.
+#[must_use]
+pub fn rollout_bucket(hostname: &str) -> u8 {
+    u8::try_from(hash(hostname) % 100).expect("Modulo 100 always fits into u8")
+}
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum TagError {
+    #[error("Tag `{0}` is not of the form `key=value`")]
+    InvalidTag(String),
+}
+
+/// Parse a single `key=value` tag
+pub fn parse_tag(tag: &str) -> Result<(String, String), TagError> {
+    match tag.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_owned(), value.trim().to_owned()))
+        }
+        _ => Err(TagError::InvalidTag(tag.to_owned())),
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+/// Selects hosts by their tags. A host matches if it has all tags of the selector.
+/// Parsed from `role=web,site=zurich`
+pub struct TagSelector(pub Tags);
+
+impl TagSelector {
+    #[must_use]
+    pub fn matches(&self, tags: &Tags) -> bool {
+        self.0.iter().all(|(key, value)| tags.get(key) == Some(value))
+    }
+}
+
+impl FromStr for TagSelector {
+    type Err = TagError;
+
+    fn from_str(selector: &str) -> Result<Self, Self::Err> {
+        selector
+            .split(',')
+            .map(parse_tag)
+            .collect::<Result<Tags, _>>()
+            .map(TagSelector)
+    }
+}
+
+impl Display for TagSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tags = self
+            .0
+            .iter()
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>();
+        write!(f, "{}", tags.join(","))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+/// Hosts a build key may publish to. A host is in scope if its name matches one of the patterns
+/// or its tags match one of the selectors
+pub struct HostScope {
+    /// Hostnames or globs e.g. `web-*`
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<TagSelector>,
+}
+
+impl HostScope {
+    #[must_use]
+    pub fn allows(&self, hostname: &str, tags: &Tags) -> bool {
+        self.hosts
+            .iter()
+            .any(|pattern| WildMatch::new(pattern).matches(hostname))
+            || self.tags.iter().any(|selector| selector.matches(tags))
+    }
+}
+
+impl Display for HostScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scope = self
+            .hosts
+            .iter()
+            .cloned()
+            .chain(self.tags.iter().map(ToString::to_string))
+            .collect::<Vec<_>>();
+        write!(f, "{}", scope.join(" "))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Add or remove tags of a host
+pub struct HostTagsRequest {
+    pub hostname: String,
+    /// Tags to add. Existing tags with the same key are overwritten
+    pub set: Tags,
+    /// Keys of the tags to remove
+    pub remove: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -37,6 +268,23 @@ pub struct HostRemoveRequest {
     pub hostname: String,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Pre-provision a host before it enrolls
+pub struct PlaceholderRequest {
+    pub hostname: String,
+    pub tags: Tags,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Host which was created by an admin but did not enroll yet. The first key approved or enrolled
+/// with its name takes over the provision state and the tags
+pub struct PlaceholderHost {
+    pub name: String,
+    pub provision_state: ProvisionState,
+    pub tags: Tags,
+    pub created: Zoned,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 /// Rename
 pub struct HostRenameRequest {
@@ -60,6 +308,19 @@ pub struct RemoteStorePath {
     pub substitutor: String,
     /// netrc File to use when downloading from the cache. Useful when using private caches
     pub netrc: Option<String>,
+    /// Commit the store path was built from
+    #[serde(default)]
+    pub commit: Option<Commit>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// A version a host reported running
+pub struct HostVersion {
+    pub store_path: StorePath,
+    /// When the host first reported this version
+    pub since: Zoned,
+    /// Commit of the version if it was published with one
+    pub commit: Option<Commit>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq)]
@@ -67,9 +328,27 @@ pub struct VerificationAttempt {
     pub key: VerifyingKey,
     pub store_path: StorePath,
     pub artifacts: VerificationArtifacts,
+    /// System hostname of the agent. Proposed as name of the host by `yeet approve`
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Name of the NixOS configuration the agent runs. See [`configuration_name`]
+    #[serde(default)]
+    pub configuration: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq)]
+/// Name of the NixOS configuration a system closure was built from e.g. `web-1` for
+/// `/nix/store/<hash>-nixos-system-web-1-25.05.20250601.abcdef`.
+/// This is `system.name` which defaults to `networking.hostName`. nix-darwin closures do not
+/// carry their name
+#[must_use]
+pub fn configuration_name(store_path: &str) -> Option<String> {
+    let name = store_path.rsplit('/').next()?;
+    let (_hash, name) = name.split_once('-')?;
+    let (configuration, _version) = name.strip_prefix("nixos-system-")?.rsplit_once('-')?;
+    (!configuration.is_empty()).then(|| configuration.to_owned())
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct VerificationArtifacts {
     pub nixos_facter: Option<String>,
 }
@@ -80,17 +359,116 @@ pub struct VerificationAcceptance {
     pub hostname: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// A verification attempt waiting for `yeet approve`
+pub struct PendingVerification {
+    pub code: u32,
+    pub key: VerifyingKey,
+    pub store_path: StorePath,
+    pub since: Zoned,
+    /// System hostname the agent proposed
+    pub hostname: Option<String>,
+    /// NixOS configuration the agent runs
+    pub configuration: Option<String>,
+    /// Platform, virtualisation and machine model from the facter report e.g.
+    /// `x86_64-linux, kvm, QEMU Standard PC (Q35 + ICH9, 2009)`
+    pub facter_summary: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct VerificationRejection {
+    pub code: u32,
+    /// Reject all further verification attempts and enrollments of the key
+    pub blocklist: bool,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq)]
 pub struct AddKey {
     pub key: VerifyingKey,
     pub level: AuthLevel,
+    /// Who or what the key belongs to e.g. `alice` or `ci-github`
+    #[serde(default)]
+    pub label: String,
+    /// The key is rejected after this time
+    #[serde(default)]
+    pub expires: Option<Zoned>,
+    /// Hosts the key may publish to. Every host if not set
+    #[serde(default)]
+    pub scope: Option<HostScope>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// An admin or build key known to the server
+pub struct KeyInfo {
+    pub key: VerifyingKey,
+    pub level: AuthLevel,
+    pub label: String,
+    pub created: Zoned,
+    /// Last time a request signed with the key was accepted
+    pub last_used: Option<Zoned>,
+    pub expires: Option<Zoned>,
+    /// Hosts the key may publish to. Every host if not set
+    #[serde(default)]
+    pub scope: Option<HostScope>,
+}
+
+impl KeyInfo {
+    #[must_use]
+    pub fn is_expired(&self, now: &Zoned) -> bool {
+        self.expires.as_ref().is_some_and(|expires| expires <= now)
+    }
 }
 
 #[expect(clippy::exhaustive_structs)]
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Copy)]
+/// Role of an admin or build key
 pub enum AuthLevel {
+    /// Publish new versions
     Build,
+    /// Everything
     Admin,
+    /// Read the status of the fleet
+    Viewer,
+    /// Run the fleet: detach, approve hosts, maintenance and rollouts but no key management
+    Operator,
+}
+
+impl AuthLevel {
+    #[must_use]
+    pub fn allows(self, permission: Permission) -> bool {
+        match self {
+            AuthLevel::Admin => true,
+            AuthLevel::Operator => matches!(permission, Permission::Read | Permission::Operate),
+            AuthLevel::Viewer => permission == Permission::Read,
+            AuthLevel::Build => permission == Permission::Publish,
+        }
+    }
+}
+
+#[expect(clippy::exhaustive_enums)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Copy)]
+/// What a route requires from the key of a request
+pub enum Permission {
+    /// Status of hosts, maintenance windows and rollouts
+    Read,
+    /// Detach and attach hosts, approve verification attempts, maintenance and rollouts
+    Operate,
+    /// Publish new versions
+    Publish,
+    /// Keys, host management, detach permissions and the audit log
+    Admin,
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let permission = match self {
+            Permission::Read => "read",
+            Permission::Operate => "operate",
+            Permission::Publish => "publish",
+            Permission::Admin => "admin",
+        };
+        f.write_str(permission)
+    }
 }
 
 // values that are needed at start:
@@ -149,8 +527,25 @@ pub struct Host {
     pub last_ping: Zoned,
     pub provision_state: ProvisionState,
     // Version with date when the update occured
-    pub version_history: Vec<(StorePath, Zoned)>,
+    pub version_history: Vec<HostVersion>,
     pub detach_allowed: Option<bool>,
+    #[serde(default)]
+    pub tags: Tags,
+    /// Versions the host was provisioned with before the current one, oldest first
+    #[serde(default)]
+    pub provision_history: Vec<RemoteStorePath>,
+    /// Overrides the maintenance windows of the groups of the host
+    #[serde(default)]
+    pub maintenance_windows: Vec<maintenance::MaintenanceWindow>,
+    /// Set while a new version is held back because the host is outside its maintenance windows
+    #[serde(default)]
+    pub update_pending_until: Option<Zoned>,
+    /// Last deployment result reported by the agent with the time it was received
+    #[serde(default)]
+    pub last_deployment: Option<(DeploymentReport, Zoned)>,
+    /// Provisioned version which failed to deploy. It is not issued again until an admin retries
+    #[serde(default)]
+    pub failed_version: Option<StorePath>,
 }
 
 impl Host {
@@ -160,13 +555,48 @@ impl Host {
             .version_history
             .last()
             .expect("version_history cannot be empty")
-            .0
+            .store_path
+    }
+
+    #[must_use]
+    pub fn latest_version(&self) -> &HostVersion {
+        self.version_history
+            .last()
+            .expect("version_history cannot be empty")
     }
+
+    /// Record a new version reported by the host. The commit is taken from the version the
+    /// server provisioned if the store path matches
     pub fn update_store_path(&mut self, store_path: String) {
-        self.version_history.push((store_path, Zoned::now()));
+        let commit = match &self.provision_state {
+            ProvisionState::Provisioned(version) | ProvisionState::Detached(version) => {
+                Some(version)
+            }
+            ProvisionState::NotSet => None,
+        }
+        .into_iter()
+        .chain(self.provision_history.iter().rev())
+        .find(|version| version.store_path == store_path)
+        .and_then(|version| version.commit.clone());
+
+        self.version_history.push(HostVersion {
+            store_path,
+            since: Zoned::now(),
+            commit,
+        });
     }
 
     pub fn push_update(&mut self, version: RemoteStorePath) {
+        // A new update is a deliberate decision to try again
+        self.failed_version = None;
+
+        if let ProvisionState::Provisioned(previous) | ProvisionState::Detached(previous) =
+            &self.provision_state
+            && *previous != version
+        {
+            self.provision_history.push(previous.clone());
+        }
+
         if self.is_provisioned() || self.is_notset() {
             self.provision_state = ProvisionState::Provisioned(version);
         } else if self.is_detached() {
@@ -174,6 +604,16 @@ impl Host {
         }
     }
 
+    /// Restore the version provisioned before the current one.
+    /// Returns the restored version or `None` if there is nothing to go back to
+    pub fn rollback(&mut self) -> Option<&RemoteStorePath> {
+        let ProvisionState::Provisioned(current) = &mut self.provision_state else {
+            return None;
+        };
+        *current = self.provision_history.pop()?;
+        Some(current)
+    }
+
     pub fn detach(&mut self) {
         if let ProvisionState::Provisioned(version) = &self.provision_state {
             self.provision_state = ProvisionState::Detached(version.clone());
@@ -269,6 +709,37 @@ pub struct VersionRequest {
     pub store_path: StorePath,
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeploymentOutcome {
+    /// `nix-store --realise` failed - the system is unchanged
+    DownloadFailed,
+    /// Setting the profile or `switch-to-configuration` failed
+    ActivationFailed,
+    /// The switch succeeded but a health check of the agent failed afterwards
+    HealthCheckFailed,
+    Switched,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// Result of a `SwitchTo` sent by the agent after it tried to switch
+pub struct DeploymentReport {
+    pub store_path: StorePath,
+    pub outcome: DeploymentOutcome,
+    /// Exit code of the last command run. `None` if it was killed by a signal
+    pub exit_code: Option<i32>,
+    /// The last lines of stderr of the failed command
+    pub stderr_tail: String,
+    /// The agent switched back to the version it ran before
+    #[serde(default)]
+    pub rolled_back: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Issue a version which failed on the host again
+pub struct RetryRequest {
+    pub hostname: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum SetDetachPermission {
     Global(bool),
@@ -283,6 +754,175 @@ pub enum DetachAction {
     AttachHost(String),
 }
 
+/// Placeholder for secrets which must not end up in the audit log
+pub const REDACTED: &str = "[redacted]";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+/// A single admin or build action recorded by the server
+pub struct AuditEntry {
+    pub time: Zoned,
+    /// Key which signed the request
+    pub key: VerifyingKey,
+    /// Name associated with the key e.g. the hostname for host keys
+    pub key_name: Option<String>,
+    pub action: AuditAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum AuditAction {
+    Update {
+        hosts: HashMap<String, StorePath>,
+        public_key: String,
+        substitutor: String,
+        /// Always [`REDACTED`] if set
+        netrc: Option<String>,
+        #[serde(default)]
+        selector: Option<TagSelector>,
+        #[serde(default)]
+        rollout: Option<RolloutPlan>,
+        #[serde(default)]
+        commit: Option<Commit>,
+    },
+    AddKey {
+        key: VerifyingKey,
+        level: AuthLevel,
+        #[serde(default)]
+        label: String,
+        #[serde(default)]
+        expires: Option<Zoned>,
+        #[serde(default)]
+        scope: Option<HostScope>,
+    },
+    RemoveKey {
+        key: VerifyingKey,
+    },
+    RemoveHost {
+        hostname: String,
+    },
+    CreatePlaceholder(PlaceholderRequest),
+    RemovePlaceholder {
+        hostname: String,
+    },
+    RenameHost {
+        current_name: String,
+        new_name: String,
+    },
+    Detach(DetachAction),
+    SetDetachPermission(SetDetachPermission),
+    SetTags(HostTagsRequest),
+    AcceptVerification {
+        code: u32,
+        hostname: String,
+    },
+    RejectVerification {
+        code: u32,
+        key: VerifyingKey,
+        blocklist: bool,
+    },
+    CreateEnrollmentToken {
+        id: Uuid,
+        hostname: Option<String>,
+        tags: Tags,
+        uses: u32,
+        expires: Zoned,
+    },
+    RevokeEnrollmentToken {
+        id: Uuid,
+    },
+    /// Signed by the key of the enrolled host
+    Enroll {
+        token: Uuid,
+        hostname: String,
+    },
+    PauseRollout {
+        id: Uuid,
+    },
+    ResumeRollout {
+        id: Uuid,
+    },
+    Rollback {
+        target: RollbackTarget,
+        /// Hosts which were actually reverted
+        hosts: Vec<String>,
+    },
+    SetMaintenance(maintenance::MaintenanceRequest),
+    RetryDeployment(RetryRequest),
+}
+
+impl AuditAction {
+    /// Hosts affected by this action. Actions on the own host (e.g. `DetachSelf`) are
+    /// attributed via [`AuditEntry::key_name`]
+    #[must_use]
+    pub fn hosts(&self) -> Vec<&str> {
+        match self {
+            AuditAction::Update { hosts, .. } => hosts.keys().map(String::as_str).collect(),
+            AuditAction::Rollback { hosts, .. } => hosts.iter().map(String::as_str).collect(),
+            AuditAction::RemoveHost { hostname }
+            | AuditAction::CreatePlaceholder(PlaceholderRequest { hostname, .. })
+            | AuditAction::RemovePlaceholder { hostname }
+            | AuditAction::AcceptVerification { hostname, .. }
+            | AuditAction::Enroll { hostname, .. }
+            | AuditAction::SetTags(HostTagsRequest { hostname, .. })
+            | AuditAction::RetryDeployment(RetryRequest { hostname })
+            | AuditAction::SetMaintenance(maintenance::MaintenanceRequest {
+                target: maintenance::MaintenanceTarget::Host(hostname),
+                ..
+            })
+            | AuditAction::Detach(
+                DetachAction::DetachHost(hostname) | DetachAction::AttachHost(hostname),
+            ) => vec![hostname.as_str()],
+            AuditAction::RenameHost {
+                current_name,
+                new_name,
+            } => vec![current_name.as_str(), new_name.as_str()],
+            AuditAction::SetDetachPermission(SetDetachPermission::PerHost(hosts)) => {
+                hosts.iter().map(|(hostname, _)| hostname.as_str()).collect()
+            }
+            AuditAction::CreateEnrollmentToken {
+                hostname: Some(hostname),
+                ..
+            } => vec![hostname.as_str()],
+            AuditAction::AddKey { .. }
+            | AuditAction::RemoveKey { .. }
+            | AuditAction::CreateEnrollmentToken { hostname: None, .. }
+            | AuditAction::RevokeEnrollmentToken { .. }
+            | AuditAction::RejectVerification { .. }
+            | AuditAction::SetMaintenance(maintenance::MaintenanceRequest {
+                target: maintenance::MaintenanceTarget::Group(_),
+                ..
+            })
+            | AuditAction::PauseRollout { .. }
+            | AuditAction::ResumeRollout { .. }
+            | AuditAction::Detach(DetachAction::DetachSelf | DetachAction::AttachSelf)
+            | AuditAction::SetDetachPermission(SetDetachPermission::Global(_)) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+/// Filter for the audit log. All set filters have to match
+pub struct AuditQuery {
+    /// Only actions affecting this host or signed by its key
+    pub host: Option<String>,
+    /// Only actions signed by this key
+    pub key: Option<VerifyingKey>,
+    pub since: Option<Timestamp>,
+    pub until: Option<Timestamp>,
+}
+
+impl AuditQuery {
+    #[must_use]
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        let time = entry.time.timestamp();
+        self.key.is_none_or(|key| key == entry.key)
+            && self.since.is_none_or(|since| time >= since)
+            && self.until.is_none_or(|until| time <= until)
+            && self.host.as_ref().is_none_or(|host| {
+                entry.key_name.as_ref() == Some(host) || entry.action.hosts().contains(&host.as_str())
+            })
+    }
+}
+
 #[inline]
 pub fn hash(value: impl std::hash::Hash) -> u64 {
     ahash::RandomState::with_seeds(1, 2, 3, 4).hash_one(value)
@@ -292,3 +932,51 @@ pub fn hash(value: impl std::hash::Hash) -> u64 {
 pub fn hash_hex(value: impl std::hash::Hash) -> String {
     format!("{:x}", hash(value))
 }
+
+#[cfg(test)]
+mod test_keys {
+    use crate::{AuthLevel, HostScope, Permission, Tags};
+
+    #[test]
+    fn test_roles() {
+        assert!(AuthLevel::Viewer.allows(Permission::Read));
+        assert!(!AuthLevel::Viewer.allows(Permission::Operate));
+        assert!(AuthLevel::Operator.allows(Permission::Operate));
+        assert!(!AuthLevel::Operator.allows(Permission::Admin));
+        assert!(!AuthLevel::Operator.allows(Permission::Publish));
+        assert!(AuthLevel::Build.allows(Permission::Publish));
+        assert!(!AuthLevel::Build.allows(Permission::Read));
+        assert!(AuthLevel::Admin.allows(Permission::Admin));
+    }
+
+    #[test]
+    fn test_host_scope() {
+        let scope = HostScope {
+            hosts: vec!["web-*".to_owned()],
+            tags: vec!["team=db".parse().unwrap()],
+        };
+        let db = Tags::from([("team".to_owned(), "db".to_owned())]);
+
+        assert!(scope.allows("web-1", &Tags::new()));
+        assert!(scope.allows("postgres", &db));
+        assert!(!scope.allows("mail", &Tags::new()));
+    }
+}
+
+#[cfg(test)]
+mod test_configuration_name {
+    use crate::configuration_name;
+
+    #[test]
+    fn test_configuration_name() {
+        assert_eq!(
+            configuration_name("/nix/store/abc-nixos-system-web-1-25.05.20250601.abcdef"),
+            Some("web-1".to_owned())
+        );
+        assert_eq!(
+            configuration_name("/nix/store/abc-darwin-system-25.05.abcdef"),
+            None
+        );
+        assert_eq!(configuration_name("/nix/store/abc-nixos-system-"), None);
+    }
+}
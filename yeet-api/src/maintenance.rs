@@ -0,0 +1,309 @@
+//! Maintenance windows restrict when a host switches to a new version.
+//!
+//! A window is written as `<days> <start>-<end> [<timezone>]` e.g. `sun 22:00-04:00 Europe/Zurich`.
+//! Days are `*`, single days, lists and ranges like `mon-fri` or `sat,sun`. If the end is
+//! not after the start the window continues on the next day.
+
+use std::{fmt::Display, str::FromStr};
+
+use jiff::{
+    SignedDuration, ToSpan as _, Zoned,
+    civil::{Time, Weekday},
+    tz::TimeZone,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{TagSelector, Tags};
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("mon", Weekday::Monday),
+    ("tue", Weekday::Tuesday),
+    ("wed", Weekday::Wednesday),
+    ("thu", Weekday::Thursday),
+    ("fri", Weekday::Friday),
+    ("sat", Weekday::Saturday),
+    ("sun", Weekday::Sunday),
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+/// Recurring time range in which a host may switch to a new version
+pub struct MaintenanceWindow {
+    /// Days on which the window opens in the order they were written
+    pub days: Vec<Weekday>,
+    /// The days as written e.g. `sat-mon`. Shown instead of the expanded days
+    day_spec: String,
+    pub start: Time,
+    pub end: Time,
+    /// IANA timezone of the window. Uses the timezone of the server if not set
+    pub timezone: Option<String>,
+}
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum WindowError {
+    #[error("Maintenance window `{0}` is not of the form `<days> <start>-<end> [<timezone>]`")]
+    InvalidFormat(String),
+    #[error("`{0}` is not a weekday - use mon, tue, wed, thu, fri, sat or sun")]
+    InvalidDay(String),
+    #[error("`{0}` is not a time of day e.g. `22:00`")]
+    InvalidTime(String),
+    #[error("Unknown timezone `{0}`")]
+    InvalidTimezone(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// Maintenance windows of all hosts matching the selector
+pub struct GroupMaintenance {
+    pub selector: TagSelector,
+    pub windows: Vec<MaintenanceWindow>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum MaintenanceTarget {
+    Host(String),
+    Group(TagSelector),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Replace the maintenance windows of a host or group. No windows removes the restriction
+pub struct MaintenanceRequest {
+    pub target: MaintenanceTarget,
+    pub windows: Vec<MaintenanceWindow>,
+}
+
+impl MaintenanceWindow {
+    fn timezone(&self) -> TimeZone {
+        self.timezone
+            .as_ref()
+            .and_then(|name| TimeZone::get(name).ok())
+            .unwrap_or_else(TimeZone::system)
+    }
+
+    fn length(&self) -> SignedDuration {
+        let length = self.end.duration_since(self.start);
+        if length.is_positive() {
+            length
+        } else {
+            length + SignedDuration::from_hours(24)
+        }
+    }
+
+    /// Start and end of every occurrence from the day before `now` until a week later
+    fn occurrences(&self, now: &Zoned) -> impl Iterator<Item = (Zoned, Zoned)> {
+        let timezone = self.timezone();
+        let today = now.with_time_zone(timezone.clone()).date();
+        let length = self.length();
+
+        (-1_i32..=7).filter_map(move |offset| {
+            let date = today.checked_add(offset.days()).ok()?;
+            if !self.days.contains(&date.weekday()) {
+                return None;
+            }
+            let start = date.to_datetime(self.start).to_zoned(timezone.clone()).ok()?;
+            let end = start.checked_add(length).ok()?;
+            Some((start, end))
+        })
+    }
+
+    #[must_use]
+    pub fn is_open(&self, now: &Zoned) -> bool {
+        self.occurrences(now)
+            .any(|(start, end)| start <= *now && *now < end)
+    }
+
+    /// The next time the window opens after `now`
+    #[must_use]
+    pub fn next_open(&self, now: &Zoned) -> Option<Zoned> {
+        self.occurrences(now)
+            .map(|(start, _end)| start)
+            .find(|start| start > now)
+    }
+}
+
+/// A host without any window may switch at any time
+#[must_use]
+pub fn is_open(windows: &[MaintenanceWindow], now: &Zoned) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.is_open(now))
+}
+
+/// The earliest time any of the windows opens after `now`
+#[must_use]
+pub fn next_open(windows: &[MaintenanceWindow], now: &Zoned) -> Option<Zoned> {
+    windows
+        .iter()
+        .filter_map(|window| window.next_open(now))
+        .min_by_key(Zoned::timestamp)
+}
+
+/// Windows of a host. Windows set on the host itself replace the windows of its groups
+#[must_use]
+pub fn windows_for(
+    host_windows: &[MaintenanceWindow],
+    tags: &Tags,
+    groups: &[GroupMaintenance],
+) -> Vec<MaintenanceWindow> {
+    if !host_windows.is_empty() {
+        return host_windows.to_vec();
+    }
+    groups
+        .iter()
+        .filter(|group| group.selector.matches(tags))
+        .flat_map(|group| group.windows.iter().cloned())
+        .collect()
+}
+
+fn parse_day(day: &str) -> Result<Weekday, WindowError> {
+    WEEKDAYS
+        .iter()
+        .find(|(name, _weekday)| name.eq_ignore_ascii_case(day))
+        .map(|(_name, weekday)| *weekday)
+        .ok_or_else(|| WindowError::InvalidDay(day.to_owned()))
+}
+
+fn parse_days(days: &str) -> Result<Vec<Weekday>, WindowError> {
+    if days == "*" {
+        return Ok(WEEKDAYS.iter().map(|(_name, weekday)| *weekday).collect());
+    }
+
+    let mut parsed: Vec<Weekday> = Vec::new();
+    let mut push = |day: Weekday| {
+        if !parsed.contains(&day) {
+            parsed.push(day);
+        }
+    };
+    for part in days.split(',') {
+        if let Some((first, last)) = part.split_once('-') {
+            // Ranges can wrap around the end of the week e.g. `fri-mon`
+            let (mut day, last) = (parse_day(first)?, parse_day(last)?);
+            push(day);
+            while day != last {
+                day = day.tomorrow();
+                push(day);
+            }
+        } else {
+            push(parse_day(part)?);
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse_time(time: &str) -> Result<Time, WindowError> {
+    time.parse()
+        .map_err(|_err| WindowError::InvalidTime(time.to_owned()))
+}
+
+impl FromStr for MaintenanceWindow {
+    type Err = WindowError;
+
+    fn from_str(window: &str) -> Result<Self, Self::Err> {
+        let invalid = || WindowError::InvalidFormat(window.to_owned());
+
+        let mut parts = window.split_whitespace();
+        let (Some(days), Some(times)) = (parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let timezone = parts.next().map(str::to_owned);
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+
+        if let Some(timezone) = &timezone {
+            TimeZone::get(timezone)
+                .map_err(|_err| WindowError::InvalidTimezone(timezone.clone()))?;
+        }
+
+        let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+        let parsed_days = parse_days(days)?;
+        let day_spec = if parsed_days.len() == WEEKDAYS.len() {
+            "*".to_owned()
+        } else {
+            days.to_lowercase()
+        };
+        Ok(MaintenanceWindow {
+            days: parsed_days,
+            day_spec,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            timezone,
+        })
+    }
+}
+
+impl Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {}-{}",
+            self.day_spec,
+            self.start.strftime("%H:%M"),
+            self.end.strftime("%H:%M")
+        )?;
+        if let Some(timezone) = &self.timezone {
+            write!(f, " {timezone}")?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for MaintenanceWindow {
+    type Error = WindowError;
+
+    fn try_from(window: String) -> Result<Self, Self::Error> {
+        window.parse()
+    }
+}
+
+impl From<MaintenanceWindow> for String {
+    fn from(window: MaintenanceWindow) -> Self {
+        window.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test_maintenance {
+    use jiff::{Zoned, civil::Weekday};
+
+    use crate::maintenance::MaintenanceWindow;
+
+    fn at(time: &str) -> Zoned {
+        format!("{time}[UTC]").parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_display() {
+        let window: MaintenanceWindow = "sat-mon 22:00-04:00 UTC".parse().unwrap();
+        assert_eq!(window.to_string(), "sat-mon 22:00-04:00 UTC");
+        assert_eq!(
+            window.days,
+            [Weekday::Saturday, Weekday::Sunday, Weekday::Monday]
+        );
+
+        let window: MaintenanceWindow = "Fri,mon-tue 01:00-02:00".parse().unwrap();
+        assert_eq!(window.to_string(), "fri,mon-tue 01:00-02:00");
+
+        let window: MaintenanceWindow = "mon,tue,wed,thu,fri,sat,sun 01:00-02:00".parse().unwrap();
+        assert_eq!(window.to_string(), "* 01:00-02:00");
+
+        assert!("sun".parse::<MaintenanceWindow>().is_err());
+        assert!("sunday 22:00-04:00".parse::<MaintenanceWindow>().is_err());
+        assert!("sun 22:00-04:00 Not/AZone".parse::<MaintenanceWindow>().is_err());
+    }
+
+    #[test]
+    fn test_overnight_window() {
+        // 2025-01-05 is a Sunday
+        let window: MaintenanceWindow = "sun 22:00-04:00 UTC".parse().unwrap();
+
+        assert!(!window.is_open(&at("2025-01-05T21:59")));
+        assert!(window.is_open(&at("2025-01-05T22:00")));
+        assert!(window.is_open(&at("2025-01-06T03:59")));
+        assert!(!window.is_open(&at("2025-01-06T04:00")));
+
+        assert_eq!(
+            window.next_open(&at("2025-01-06T04:00")).unwrap().timestamp(),
+            at("2025-01-12T22:00").timestamp()
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Events yeetd sends to the configured webhook URLs.
+//!
+//! Each event is POSTed as JSON and signed with the webhook key of the server. The signature
+//! covers `@method`, `@target-uri` and `content-digest` so receivers can verify it with the
+//! public key of the server. Deliveries are retried, so the same `id` may arrive more than once
+//! and events of different hosts are not guaranteed to arrive in order.
+
+use std::collections::BTreeMap;
+
+use jiff::Zoned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{Commit, DeploymentReport, StorePath};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A build machine published new versions
+    Published {
+        hosts: BTreeMap<String, StorePath>,
+        commit: Option<Commit>,
+        /// Set if the versions are shipped in waves
+        rollout: Option<Uuid>,
+    },
+    /// The host reported that it runs its provisioned version
+    Switched {
+        hostname: String,
+        store_path: StorePath,
+    },
+    /// The host could not switch to its provisioned version
+    SwitchFailed {
+        hostname: String,
+        report: DeploymentReport,
+    },
+    Detached {
+        hostname: String,
+    },
+    Attached {
+        hostname: String,
+    },
+    /// A new host waits for `yeet approve`. The code is not included on purpose - it has to be
+    /// read from the host itself
+    VerificationPending {
+        store_path: StorePath,
+    },
+    /// The host did not check in for longer than the configured threshold
+    HostStale {
+        hostname: String,
+        last_ping: Zoned,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+/// Body of a webhook request
+pub struct WebhookEvent {
+    /// Stays the same across retries - use it to drop duplicates
+    pub id: Uuid,
+    pub time: Zoned,
+    #[serde(flatten)]
+    pub event: Event,
+}
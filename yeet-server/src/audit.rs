@@ -0,0 +1,92 @@
+//! Append-only log of every admin and build action
+//!
+//! Each entry is a single JSON line. The file is only ever appended to and synced after each entry.
+//! Actions are recorded once they succeeded while the state lock is still held,
+//! so the order of the log matches the order in which the actions were applied.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead as _, BufReader, Write as _},
+    path::{Path, PathBuf},
+};
+
+use axum::http::StatusCode;
+use axum_thiserror::ErrorStatus;
+use ed25519_dalek::VerifyingKey;
+use jiff::Zoned;
+use parking_lot::Mutex;
+use thiserror::Error;
+
+use crate::state::AppState;
+
+#[derive(Error, Debug, ErrorStatus)]
+pub enum AuditError {
+    #[error("Could not access the audit log: {0}")]
+    #[status(StatusCode::INTERNAL_SERVER_ERROR)]
+    Io(#[from] io::Error),
+
+    #[error("Could not serialize the audit entry: {0}")]
+    #[status(StatusCode::INTERNAL_SERVER_ERROR)]
+    Json(#[from] serde_json::Error),
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open<P: AsRef<Path>>(location: P) -> Result<Self, AuditError> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&location)?;
+        Ok(Self {
+            path: location.as_ref().to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append an action signed by `key` to the log
+    pub fn record(
+        &self,
+        state: &AppState,
+        key: &VerifyingKey,
+        action: api::AuditAction,
+    ) -> Result<(), AuditError> {
+        let entry = api::AuditEntry {
+            time: Zoned::now(),
+            key: *key,
+            key_name: state.key_name(key),
+            action,
+        };
+
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock();
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// All entries matching the query, oldest first
+    pub fn query(&self, query: &api::AuditQuery) -> Result<Vec<api::AuditEntry>, AuditError> {
+        // Hold the lock so we never read a partially written line
+        let _file = self.file.lock();
+        let reader = BufReader::new(File::open(&self.path)?);
+
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let entry: api::AuditEntry = serde_json::from_str(&line)?;
+            if query.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
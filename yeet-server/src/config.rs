@@ -0,0 +1,574 @@
+//! Configuration of yeetd.
+//!
+//! Read from the TOML file given with `--config` (or `YEET_CONFIG`) and overridden by `YEET_`
+//! environment variables e.g. `YEET_PORT=8080` overrides `port = 4337`. Lists can be given
+//! comma separated in the environment: `YEET_LISTEN=0.0.0.0:4337,[::]:4337`.
+//! Everything is validated in [`Config::load`] so that a bad config fails at startup.
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use api::key::{KeyError, get_secret_key, get_verify_key, parse_verify_key};
+use ed25519_dalek::VerifyingKey;
+use figment::{
+    Figment,
+    providers::{Env, Format as _, Serialized, Toml},
+};
+use ipnet::IpNet;
+use jiff::SignedDuration;
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{self, SeqAccess, Visitor},
+};
+use thiserror::Error;
+
+use crate::{
+    store::{Backend, StoreError},
+    webhook::WebhookConfig,
+};
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Config file {0} does not exist")]
+    MissingFile(PathBuf),
+
+    #[error(transparent)]
+    Parse(#[from] Box<figment::Error>),
+
+    #[error("Unknown config keys {} - check for typos", .0.join(", "))]
+    UnknownKeys(Vec<String>),
+
+    #[error("`storage`: {0}")]
+    Storage(#[from] StoreError),
+
+    #[error("`listen`: `{0}` is not of the form `<host>:<port>`")]
+    InvalidListen(String),
+
+    #[error("`{0}` has to be greater than 0")]
+    Zero(&'static str),
+
+    #[error("`verify_allow`: `{0}` is not a network of the form `10.0.0.0/8`")]
+    InvalidNetwork(String),
+
+    #[error("`init_key`: could not read {location}: {source}")]
+    InitKey { location: String, source: KeyError },
+
+    #[error("`tls_cert` and `tls_key` have to be set together")]
+    IncompleteTls,
+
+    #[error("`webhooks` are set but there is no `webhook_key` to sign the events with")]
+    MissingWebhookKey,
+
+    #[error("`webhook_key`: could not read {location}: {source}")]
+    WebhookKey { location: String, source: KeyError },
+
+    #[error("`metrics_token`: could not read {location}: {source}")]
+    MetricsToken { location: String, source: io::Error },
+
+    #[error("`metrics_token`: {0} is empty")]
+    EmptyMetricsToken(String),
+}
+
+/// The admin key used when the server starts without any admin
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum InitKey {
+    /// `init_key = { inline = "ssh-ed25519 AAAA..." }`
+    Inline { inline: String },
+    /// `init_key = "/etc/yeet/admin.pub"`
+    Path(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default)]
+/// Limits of `POST /system/verify` which anyone can call
+pub struct VerificationLimits {
+    /// Open verification attempts at the same time
+    pub max_attempts: usize,
+    /// Minutes until an attempt which was not approved expires
+    pub expire_after: i64,
+}
+
+impl Default for VerificationLimits {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            expire_after: 15,
+        }
+    }
+}
+
+impl VerificationLimits {
+    pub fn expire_after(&self) -> SignedDuration {
+        SignedDuration::from_mins(self.expire_after)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+/// Abuse protection of `POST /system/verify` - see `crate::ratelimit`
+pub struct EnrollmentLimits {
+    /// Verification attempts per source address and minute
+    pub verify_rate: u32,
+    /// Largest accepted verification attempt in bytes. Mostly the facter report
+    pub verify_body_limit: usize,
+    /// Networks which may enroll e.g. `10.0.0.0/8`. Everyone if empty
+    #[serde(deserialize_with = "string_or_list")]
+    pub verify_allow: Vec<String>,
+}
+
+impl Default for EnrollmentLimits {
+    fn default() -> Self {
+        Self {
+            verify_rate: 5,
+            verify_body_limit: 1024 * 1024,
+            verify_allow: Vec::new(),
+        }
+    }
+}
+
+impl EnrollmentLimits {
+    pub fn verify_allow(&self) -> Result<Vec<IpNet>, ConfigError> {
+        self.verify_allow
+            .iter()
+            .map(|network| {
+                network
+                    .parse()
+                    .map_err(|_err| ConfigError::InvalidNetwork(network.clone()))
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(default)]
+/// Replay protection of signed requests - see `crate::replay`
+pub struct ReplayLimits {
+    /// Seconds the `created` time of a signature may differ from the server time
+    pub signature_skew: i64,
+    /// Reject signatures without a nonce. Agents older than the replay protection do not send one
+    pub require_nonce: bool,
+    /// Nonces remembered at most. Requests are rejected while the cache is full
+    pub nonce_cache_size: usize,
+}
+
+impl Default for ReplayLimits {
+    fn default() -> Self {
+        Self {
+            signature_skew: 300,
+            require_nonce: false,
+            nonce_cache_size: 100_000,
+        }
+    }
+}
+
+/// Every key of [`Config`]. The limit groups are flattened into the config which makes serde
+/// ignore unknown keys - `check_unknown_keys` rejects them instead
+const KEYS: [&str; 23] = [
+    "listen",
+    "host",
+    "port",
+    "storage",
+    "state",
+    "snapshots",
+    "audit",
+    "save_interval",
+    "init_key",
+    "max_attempts",
+    "expire_after",
+    "verify_rate",
+    "verify_body_limit",
+    "verify_allow",
+    "signature_skew",
+    "require_nonce",
+    "nonce_cache_size",
+    "webhooks",
+    "webhook_key",
+    "webhook_stale_after",
+    "tls_cert",
+    "tls_key",
+    "metrics_token",
+];
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+    /// Addresses to listen on. Takes precedence over `host` and `port`
+    #[serde(deserialize_with = "string_or_list")]
+    pub listen: Vec<String>,
+    pub host: String,
+    pub port: u16,
+    /// `json` or `sqlite`
+    pub storage: String,
+    /// Defaults to `state.json` or `state.sqlite` depending on the storage
+    pub state: Option<PathBuf>,
+    /// Old versions of the state to keep (JSON storage only). Taken on startup and then hourly
+    pub snapshots: usize,
+    pub audit: PathBuf,
+    /// Milliseconds between writes of the state
+    pub save_interval: u64,
+    pub init_key: Option<InitKey>,
+    #[serde(flatten)]
+    pub verification: VerificationLimits,
+    #[serde(flatten)]
+    pub enrollment: EnrollmentLimits,
+    #[serde(flatten)]
+    pub replay: ReplayLimits,
+    #[serde(deserialize_with = "string_or_list")]
+    pub webhooks: Vec<String>,
+    pub webhook_key: Option<PathBuf>,
+    /// Minutes after which a host which did not check in is reported
+    pub webhook_stale_after: i64,
+    /// PEM certificate chain. Serves HTTPS if set together with `tls_key`
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate
+    pub tls_key: Option<PathBuf>,
+    /// File with the bearer token Prometheus scrapes `/metrics` with. Disabled if not set
+    pub metrics_token: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: Vec::new(),
+            host: "localhost".to_owned(),
+            port: 4337,
+            storage: "json".to_owned(),
+            state: None,
+            snapshots: 5,
+            audit: PathBuf::from("audit.jsonl"),
+            save_interval: 500,
+            init_key: None,
+            verification: VerificationLimits::default(),
+            enrollment: EnrollmentLimits::default(),
+            replay: ReplayLimits::default(),
+            webhooks: Vec::new(),
+            webhook_key: None,
+            webhook_stale_after: 15,
+            tls_cert: None,
+            tls_key: None,
+            metrics_token: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut figment = Figment::from(Serialized::defaults(Config::default()));
+        if let Some(path) = path {
+            if !path.exists() {
+                return Err(ConfigError::MissingFile(path.to_owned()));
+            }
+            figment = figment.merge(Toml::file(path));
+        }
+        let figment = figment.merge(Env::prefixed("YEET_").ignore(&["config"]));
+        check_unknown_keys(&figment)?;
+        let config: Config = figment.extract().map_err(Box::new)?;
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.backend()?;
+
+        for address in self.listen_addresses() {
+            if !address
+                .rsplit_once(':')
+                .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+            {
+                return Err(ConfigError::InvalidListen(address));
+            }
+        }
+
+        if self.save_interval == 0 {
+            return Err(ConfigError::Zero("save_interval"));
+        }
+        if self.verification.max_attempts == 0 {
+            return Err(ConfigError::Zero("max_attempts"));
+        }
+        if self.verification.expire_after <= 0 {
+            return Err(ConfigError::Zero("expire_after"));
+        }
+        if self.enrollment.verify_rate == 0 {
+            return Err(ConfigError::Zero("verify_rate"));
+        }
+        if self.enrollment.verify_body_limit == 0 {
+            return Err(ConfigError::Zero("verify_body_limit"));
+        }
+        self.enrollment.verify_allow()?;
+        if self.replay.signature_skew <= 0 {
+            return Err(ConfigError::Zero("signature_skew"));
+        }
+        if self.replay.nonce_cache_size == 0 {
+            return Err(ConfigError::Zero("nonce_cache_size"));
+        }
+        if self.webhook_stale_after <= 0 {
+            return Err(ConfigError::Zero("webhook_stale_after"));
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err(ConfigError::IncompleteTls);
+        }
+
+        self.init_key()?;
+        self.webhook_config()?;
+        self.metrics_token()?;
+        Ok(())
+    }
+
+    pub fn backend(&self) -> Result<Backend, StoreError> {
+        self.storage.parse()
+    }
+
+    pub fn state_location(&self) -> Result<PathBuf, StoreError> {
+        let backend = self.backend()?;
+        Ok(self
+            .state
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(backend.default_location())))
+    }
+
+    pub fn listen_addresses(&self) -> Vec<String> {
+        if self.listen.is_empty() {
+            vec![format!("{}:{}", self.host, self.port)]
+        } else {
+            self.listen.clone()
+        }
+    }
+
+    /// Certificate and key if TLS is enabled
+    pub fn tls(&self) -> Option<(&Path, &Path)> {
+        Some((self.tls_cert.as_deref()?, self.tls_key.as_deref()?))
+    }
+
+    pub fn save_interval(&self) -> Duration {
+        Duration::from_millis(self.save_interval)
+    }
+
+    pub fn init_key(&self) -> Result<Option<VerifyingKey>, ConfigError> {
+        let Some(init_key) = &self.init_key else {
+            return Ok(None);
+        };
+        let (location, key) = match init_key {
+            InitKey::Inline { inline } => ("inline key".to_owned(), parse_verify_key(inline)),
+            InitKey::Path(path) => (path.display().to_string(), get_verify_key(path)),
+        };
+        key.map(Some)
+            .map_err(|source| ConfigError::InitKey { location, source })
+    }
+
+    pub fn webhook_config(&self) -> Result<WebhookConfig, ConfigError> {
+        let key = if self.webhooks.is_empty() {
+            None
+        } else {
+            let path = self
+                .webhook_key
+                .as_ref()
+                .ok_or(ConfigError::MissingWebhookKey)?;
+            Some(
+                get_secret_key(path).map_err(|source| ConfigError::WebhookKey {
+                    location: path.display().to_string(),
+                    source,
+                })?,
+            )
+        };
+
+        Ok(WebhookConfig {
+            urls: self.webhooks.clone(),
+            key,
+            stale_after: SignedDuration::from_mins(self.webhook_stale_after),
+        })
+    }
+
+    pub fn metrics_token(&self) -> Result<Option<String>, ConfigError> {
+        let Some(path) = &self.metrics_token else {
+            return Ok(None);
+        };
+        let location = path.display().to_string();
+        let token = fs::read_to_string(path)
+            .map_err(|source| ConfigError::MetricsToken {
+                location: location.clone(),
+                source,
+            })?
+            .trim()
+            .to_owned();
+        if token.is_empty() {
+            return Err(ConfigError::EmptyMetricsToken(location));
+        }
+        Ok(Some(token))
+    }
+}
+
+/// A misspelled key would otherwise silently fall back to its default
+fn check_unknown_keys(figment: &Figment) -> Result<(), ConfigError> {
+    let mut unknown: Vec<String> = figment
+        .data()
+        .map_err(Box::new)?
+        .into_values()
+        .flat_map(|dict| dict.into_keys())
+        .filter(|key| !KEYS.contains(&key.as_str()))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    unknown.sort();
+    unknown.dedup();
+    Err(ConfigError::UnknownKeys(unknown))
+}
+
+/// Accept `["a", "b"]` as well as `"a,b"` which is how lists are given in the environment
+fn string_or_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    struct StringOrList;
+
+    impl<'de> Visitor<'de> for StringOrList {
+        type Value = Vec<String>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a list of strings or a comma separated string")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            Ok(value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_owned)
+                .collect())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut list = Vec::new();
+            while let Some(item) = seq.next_element()? {
+                list.push(item);
+            }
+            Ok(list)
+        }
+    }
+
+    deserializer.deserialize_any(StringOrList)
+}
+
+#[cfg(test)]
+mod test_config {
+    use figment::{
+        Figment,
+        providers::{Format as _, Serialized, Toml},
+    };
+
+    use crate::config::{Config, ConfigError, InitKey, KEYS, check_unknown_keys};
+
+    fn parse(toml: &str) -> Config {
+        Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string(toml))
+            .extract()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_listen() {
+        let config = parse("port = 8080");
+        assert_eq!(config.listen_addresses(), vec!["localhost:8080"]);
+
+        let config = parse(r#"listen = "0.0.0.0:4337, [::]:4337""#);
+        assert_eq!(config.listen_addresses(), vec!["0.0.0.0:4337", "[::]:4337"]);
+
+        let config = parse(r#"listen = ["localhost"]"#);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidListen(_))
+        ));
+    }
+
+    #[test]
+    fn test_init_key() {
+        let config = parse(r#"init_key = "/etc/yeet/admin.pub""#);
+        assert_eq!(
+            config.init_key,
+            Some(InitKey::Path("/etc/yeet/admin.pub".into()))
+        );
+
+        let config = parse(r#"init_key = { inline = "not a key" }"#);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InitKey { .. })
+        ));
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let figment = Figment::from(Serialized::defaults(Config::default()))
+            .merge(Toml::string("max_attempt = 3\nport = 8080"));
+        assert!(matches!(
+            check_unknown_keys(&figment),
+            Err(ConfigError::UnknownKeys(keys)) if keys == ["max_attempt"]
+        ));
+
+        // `KEYS` has to list every key of the config
+        let config = Config {
+            state: Some("state.json".into()),
+            init_key: Some(InitKey::Path("admin.pub".into())),
+            webhook_key: Some("webhook.key".into()),
+            tls_cert: Some("cert.pem".into()),
+            tls_key: Some("key.pem".into()),
+            metrics_token: Some("metrics.token".into()),
+            ..Config::default()
+        };
+        let mut keys: Vec<String> = Figment::from(Serialized::defaults(config))
+            .data()
+            .unwrap()
+            .into_values()
+            .flat_map(|dict| dict.into_keys())
+            .collect();
+        keys.sort();
+        let mut expected = KEYS.map(str::to_owned);
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn test_limits() {
+        let config = parse("max_attempts = 0");
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Zero("max_attempts"))
+        ));
+
+        let config = parse(r#"verify_allow = "10.0.0.0/8, 192.168.0.1""#);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidNetwork(network)) if network == "192.168.0.1"
+        ));
+
+        let config = parse(r#"webhooks = ["https://example.com"]"#);
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::MissingWebhookKey)
+        ));
+    }
+
+    #[test]
+    fn test_metrics_token() {
+        assert_eq!(parse("").metrics_token().unwrap(), None);
+
+        let path = std::env::temp_dir().join(format!("yeet-metrics-token-{}", std::process::id()));
+        std::fs::write(&path, "secret\n").unwrap();
+        let config = parse(&format!("metrics_token = {:?}", path.display().to_string()));
+        assert_eq!(config.metrics_token().unwrap().as_deref(), Some("secret"));
+
+        std::fs::write(&path, "\n").unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::EmptyMetricsToken(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::MetricsToken { .. })
+        ));
+    }
+}
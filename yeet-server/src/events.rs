@@ -0,0 +1,43 @@
+//! Wakes up agents waiting on `/system/check/wait` when a provision state changes.
+//!
+//! Routes which change what [`AppState::system_check`](crate::state::AppState::system_check)
+//! returns call [`ProvisionEvents::notify`]. Waiting requests then check their host again.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::sync::watch;
+
+pub struct ProvisionEvents {
+    changed: watch::Sender<()>,
+    shutting_down: AtomicBool,
+}
+
+impl Default for ProvisionEvents {
+    fn default() -> Self {
+        Self {
+            changed: watch::Sender::new(()),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+}
+
+impl ProvisionEvents {
+    /// Receiver which resolves `changed()` on the next notification after subscribing
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.changed.subscribe()
+    }
+
+    pub fn notify(&self) {
+        self.changed.send_replace(());
+    }
+
+    /// Release all waiting requests so that the graceful shutdown does not wait for them
+    pub fn shutdown(&self) {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        self.notify();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::Relaxed)
+    }
+}
@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use axum::{
     Json,
-    extract::{FromRequest, FromRequestParts, Request},
+    extract::{FromRef, FromRequest, FromRequestParts, Request},
     http::{self, HeaderMap, StatusCode, header},
 };
 use ed25519_dalek::VerifyingKey;
@@ -10,55 +10,106 @@ use httpsig_hyper::{
     ContentDigest as _, MessageSignature as _, MessageSignatureReq as _, RequestContentDigest as _,
     prelude::{AlgorithmName, PublicKey},
 };
+use jiff::Zoned;
 use parking_lot::RwLock;
 use serde::de::DeserializeOwned;
 
-use crate::{AppState, error::WithStatusCode as _};
+use crate::{AppState, error::WithStatusCode as _, metrics::Metrics, replay::ReplayGuard};
 
 pub struct HttpSig(pub VerifyingKey);
 
-impl FromRequestParts<Arc<RwLock<AppState>>> for HttpSig {
+impl<S> FromRequestParts<S> for HttpSig
+where
+    Arc<RwLock<AppState>>: FromRef<S>,
+    Arc<Metrics>: FromRef<S>,
+    Arc<ReplayGuard>: FromRef<S>,
+    S: Send + Sync,
+{
     type Rejection = (StatusCode, String);
 
     async fn from_request_parts(
         parts: &mut axum::http::request::Parts,
-        state: &Arc<RwLock<AppState>>,
+        state: &S,
     ) -> Result<Self, Self::Rejection> {
-        let req = http::Request::from_parts(parts.clone(), String::new());
-
-        let keyids = req.get_key_ids().with_code(StatusCode::BAD_REQUEST)?;
-        if keyids.len() != 1 {
-            return Err((
-                StatusCode::BAD_REQUEST,
-                "KeyIDs must be exactly one".to_owned(),
-            ));
+        let verified = verify_signature(
+            parts,
+            &Arc::<RwLock<AppState>>::from_ref(state),
+            &Arc::<ReplayGuard>::from_ref(state),
+        )
+        .await;
+        // The state being locked or a full nonce cache is not the fault of the client
+        if verified
+            .as_ref()
+            .is_err_and(|(status, _msg)| *status == StatusCode::BAD_REQUEST)
+        {
+            Arc::<Metrics>::from_ref(state).signature_failed();
         }
+        verified.map(HttpSig)
+    }
+}
 
-        let (_signature, keyid) = keyids
-            .first()
-            .expect("This is safe as long as we check the keyid length");
+async fn verify_signature(
+    parts: &axum::http::request::Parts,
+    state: &RwLock<AppState>,
+    replay: &ReplayGuard,
+) -> Result<VerifyingKey, (StatusCode, String)> {
+    let req = http::Request::from_parts(parts.clone(), String::new());
+
+    let keyids = req.get_key_ids().with_code(StatusCode::BAD_REQUEST)?;
+    if keyids.len() != 1 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "KeyIDs must be exactly one".to_owned(),
+        ));
+    }
+
+    let (_signature, keyid) = keyids
+        .first()
+        .expect("This is safe as long as we check the keyid length");
 
-        let Some(verifying_key) = state
+    let (verifying_key, touch) = {
+        let state = state
             .try_read()
             .ok_or("Internal State currently not available - try again later")
-            .with_code(StatusCode::INTERNAL_SERVER_ERROR)?
-            .get_key_by_id(keyid)
-        else {
+            .with_code(StatusCode::INTERNAL_SERVER_ERROR)?;
+        let Some(verifying_key) = state.get_key_by_id(keyid) else {
             return Err((
                 StatusCode::BAD_REQUEST,
                 "The KeyID is not registered".to_owned(),
             ));
         };
+        (
+            verifying_key,
+            state.needs_touch(&verifying_key, &Zoned::now()),
+        )
+    };
 
-        let pub_key = PublicKey::from_bytes(AlgorithmName::Ed25519, verifying_key.as_bytes())
-            .with_code(StatusCode::BAD_REQUEST)?;
-
-        req.verify_message_signature(&pub_key, Some(keyid))
-            .await
-            .with_code(StatusCode::BAD_REQUEST)?;
-
-        Ok(HttpSig(verifying_key))
+    let pub_key = PublicKey::from_bytes(AlgorithmName::Ed25519, verifying_key.as_bytes())
+        .with_code(StatusCode::BAD_REQUEST)?;
+
+    req.verify_message_signature(&pub_key, Some(keyid))
+        .await
+        .with_code(StatusCode::BAD_REQUEST)?;
+
+    // Only remember nonces of valid signatures - otherwise anyone could fill the cache
+    let signatures = req
+        .get_signature_params()
+        .with_code(StatusCode::BAD_REQUEST)?;
+    let params = signatures
+        .values()
+        .next()
+        .ok_or("Signature parameters missing")
+        .with_code(StatusCode::BAD_REQUEST)?;
+    replay
+        .check(params)
+        .map_err(|err| (err.status(), err.to_string()))?;
+
+    // Best effort - agent pings must not wait for the write lock just to record a key use
+    if touch && let Some(mut state) = state.try_write() {
+        state.touch_key(&verifying_key);
     }
+
+    Ok(verifying_key)
 }
 
 pub struct VerifiedJson<T>(pub T);
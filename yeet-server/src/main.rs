@@ -1,137 +1,418 @@
 //! Yeet that Config
 
-use std::{
-    env,
-    fs::{File, OpenOptions},
-    hash::{DefaultHasher, Hash as _, Hasher as _},
-    os::unix::prelude::FileExt as _,
-    sync::Arc,
-    time::Duration,
-};
+use std::{env, net::SocketAddr, path::PathBuf, process, sync::Arc, time::Duration};
 
-use api::key::get_verify_key;
 use axum::{
     Router,
+    extract::{DefaultBodyLimit, FromRef},
+    middleware,
     routing::{get, post},
 };
+use axum_server::tls_rustls::RustlsConfig;
+use clap::{Parser, Subcommand};
 use parking_lot::RwLock;
 use routes::status;
-use tokio::{net::TcpListener, time::interval};
+use serde_json::Value;
+use tokio::{
+    net::TcpListener,
+    signal::unix::{SignalKind, signal},
+    sync::{oneshot, watch},
+    time::interval,
+};
 
 use crate::{
+    audit::AuditLog,
+    config::Config,
+    events::ProvisionEvents,
+    metrics::Metrics,
+    ratelimit::VerifyLimiter,
+    replay::ReplayGuard,
     routes::{
-        detach, host,
-        key::{add_key, remove_key},
-        system_check::system_check,
+        audit::audit,
+        detach, enroll, host,
+        key::{add_key, list_keys, remove_key},
+        system_check::{system_check, system_check_wait},
         update::update_hosts,
-        verify::{add_verification_attempt, is_host_verified, verify_attempt},
+        verify::{
+            add_verification_attempt, is_host_verified, pending_verifications, reject_verification,
+            verify_attempt,
+        },
     },
     state::AppState,
+    migration::SCHEMA_VERSION,
+    store::StateStore,
 }; // TODO: is this enough or do we need to use rand_chacha?
 
+mod audit;
+mod config;
 mod error;
+mod events;
 mod httpsig;
+mod metrics;
+mod migration;
+mod ratelimit;
+mod replay;
+mod rollout;
 mod state;
+mod store;
+mod tls;
+mod webhook;
 mod routes {
+    pub mod audit;
+    pub mod deployment;
     pub mod detach;
+    pub mod enroll;
     pub mod host;
     pub mod key;
+    pub mod rollout;
     pub mod status;
     pub mod system_check;
     pub mod update;
     pub mod verify;
 }
 
+/// Everything the routes can extract with `State`
+#[derive(Clone, FromRef)]
+struct ServerState {
+    state: Arc<RwLock<AppState>>,
+    audit: Arc<AuditLog>,
+    events: Arc<ProvisionEvents>,
+    metrics: Arc<Metrics>,
+    replay: Arc<ReplayGuard>,
+    verify_limiter: Arc<VerifyLimiter>,
+}
+
+/// Idle TLS connections are closed forcefully after this time on shutdown
+const TLS_SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Yeetd {
+    /// TOML config file. `YEET_` environment variables override its values
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Migrate the stored state to the schema of this yeetd version.
+    /// The server also migrates automatically on startup
+    Migrate {
+        /// Only show which migrations would run and what they would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
 #[tokio::main]
 #[expect(
     clippy::expect_used,
     clippy::print_stdout,
+    clippy::print_stderr,
+    clippy::exit,
     reason = "allow in server main"
 )]
 async fn main() {
-    let mut state = File::open("state.json")
-        .map(serde_json::from_reader)
-        .unwrap_or(Ok(AppState::default()))
-        .expect("Could not parse state.json - missing migration");
+    let args = Yeetd::parse();
+
+    let config_path = args
+        .config
+        .or_else(|| env::var_os("YEET_CONFIG").map(PathBuf::from));
+    let config = Config::load(config_path.as_deref()).unwrap_or_else(|err| {
+        eprintln!("Invalid configuration: {err}");
+        process::exit(1);
+    });
+
+    let mut store = config
+        .backend()
+        .and_then(|backend| backend.open(config.state_location()?, config.snapshots))
+        .expect("Could not open state storage");
+
+    let stored = store.load().expect("Could not load state");
+
+    if let Some(Command::Migrate { dry_run }) = args.command {
+        migrate(&mut *store, stored, dry_run);
+        return;
+    }
+
+    let mut state = match stored {
+        Some(mut stored) => {
+            for migration in migration::migrate(&mut stored).expect("Could not migrate state") {
+                println!("Migrated state {migration}");
+            }
+            serde_json::from_value(stored).expect("Could not parse state - missing migration")
+        }
+        None => AppState::default(),
+    };
+    state.set_verification_limits(config.verification);
 
     // TODO: make this interactive if interactive shell found
     if !state.has_admin_credential() {
-        // TODO: also accept the key directly
-        let key_location = env::var("YEET_INIT_KEY")
-            .expect("Cannot start without an init key. Set it via `YEET_INIT_KEY`");
-
-        let key = get_verify_key(key_location).expect("Not a valid key {key_location}");
-        state.add_key(key, api::AuthLevel::Admin);
+        let key = config.init_key().expect("Validated on load").expect(
+            "Cannot start without an init key. Set `init_key` in the config or `YEET_INIT_KEY`",
+        );
+        state.add_key(api::AddKey {
+            key,
+            level: api::AuthLevel::Admin,
+            label: "init".to_owned(),
+            expires: None,
+            scope: None,
+        });
     }
 
+    let audit = Arc::new(AuditLog::open(&config.audit).expect("Could not open the audit log"));
+
     let state = Arc::new(RwLock::new(state));
-    {
-        let state = Arc::clone(&state);
-        tokio::spawn(async move { save_state(&state).await });
+    let (shutdown, shutdown_rx) = oneshot::channel();
+    let save = tokio::spawn(save_state(
+        Arc::clone(&state),
+        store,
+        config.save_interval(),
+        shutdown_rx,
+    ));
+    tokio::spawn(webhook::deliver(
+        Arc::clone(&state),
+        config.webhook_config().expect("Validated on load"),
+    ));
+
+    let tls = match config.tls() {
+        Some((cert, key)) => {
+            let tls = RustlsConfig::from_pem_file(cert, key)
+                .await
+                .unwrap_or_else(|err| {
+                    eprintln!("Could not load TLS certificate {}: {err}", cert.display());
+                    process::exit(1);
+                });
+            tokio::spawn(tls::reload_on_hangup(
+                tls.clone(),
+                cert.to_owned(),
+                key.to_owned(),
+            ));
+            Some(tls)
+        }
+        None => None,
     };
 
-    let port = env::var("YEET_PORT").unwrap_or("4337".to_owned());
-    let host = env::var("YEET_HOST").unwrap_or("localhost".to_owned());
+    let mut listeners = Vec::new();
+    for address in config.listen_addresses() {
+        let listener = TcpListener::bind(&address).await.unwrap_or_else(|err| {
+            eprintln!("Could not listen on {address}: {err}");
+            process::exit(1);
+        });
+        listeners.push(listener);
+    }
+
+    let events = Arc::new(ProvisionEvents::default());
+    let verify_limiter = VerifyLimiter::new(
+        config.enrollment.verify_rate,
+        config
+            .enrollment
+            .verify_allow()
+            .expect("Validated when the config was loaded"),
+    );
+    let router = routes(
+        ServerState {
+            state,
+            audit,
+            events: Arc::clone(&events),
+            metrics: Arc::new(Metrics::new(
+                config.metrics_token().expect("Validated on load"),
+            )),
+            replay: Arc::new(ReplayGuard::new(config.replay)),
+            verify_limiter: Arc::new(verify_limiter),
+        },
+        config.enrollment.verify_body_limit,
+    );
+
+    let (stop, stopped) = watch::channel(false);
+    let tls_handle = axum_server::Handle::new();
+    let servers: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            if let Some(tls) = &tls {
+                let listener = listener
+                    .into_std()
+                    .expect("Could not hand the listener to rustls");
+                return tokio::spawn(
+                    axum_server::from_tcp_rustls(listener, tls.clone())
+                        .handle(tls_handle.clone())
+                        .serve(
+                            router
+                                .clone()
+                                .into_make_service_with_connect_info::<SocketAddr>(),
+                        ),
+                );
+            }
+
+            let mut stopped = stopped.clone();
+            tokio::spawn(
+                axum::serve(
+                    listener,
+                    router
+                        .clone()
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(async move {
+                    let _ = stopped.wait_for(|stop| *stop).await;
+                })
+                .into_future(),
+            )
+        })
+        .collect();
+
+    shutdown_signal().await;
+    // Waiting agents would hold up the shutdown until their long poll times out
+    events.shutdown();
+    let _ = stop.send(true);
+    tls_handle.graceful_shutdown(Some(TLS_SHUTDOWN_GRACE));
+    for server in servers {
+        server
+            .await
+            .expect("Server task panicked")
+            .expect("Could not start axum");
+    }
 
-    let listener = TcpListener::bind(format!("{host}:{port}"))
-        .await
-        .expect("Could not bind to port");
-    axum::serve(listener, routes(state))
-        .await
-        .expect("Could not start axum");
+    // No more requests are handled - write the latest state before exiting
+    let _ = shutdown.send(());
+    save.await.expect("Could not persist state on shutdown");
 }
 
-fn routes(state: Arc<RwLock<AppState>>) -> Router {
+#[expect(clippy::expect_used, reason = "Cannot shut down gracefully without")]
+async fn shutdown_signal() {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("Could not install the SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+}
+
+/// The bodies of the unauthenticated `POST /system/verify` and `POST /system/enroll` are limited
+/// to `verify_body_limit` bytes
+fn routes(state: ServerState, verify_body_limit: usize) -> Router {
     Router::new()
         .route("/system/check", post(system_check))
+        .route("/system/check/wait", post(system_check_wait))
         .route("/system/update", post(update_hosts))
+        .route("/system/report", post(routes::deployment::report_deployment))
         .route("/system/verify/accept", post(verify_attempt))
+        .route("/system/verify/pending", get(pending_verifications))
+        .route("/system/verify/reject", post(reject_verification))
         .route("/system/verify", get(is_host_verified))
-        .route("/system/verify", post(add_verification_attempt))
+        .route(
+            "/system/verify",
+            post(add_verification_attempt)
+                .layer(DefaultBodyLimit::max(verify_body_limit))
+                .route_layer(middleware::from_fn_with_state(
+                    Arc::clone(&state.verify_limiter),
+                    ratelimit::limit_verify,
+                )),
+        )
+        .route(
+            "/system/enroll",
+            post(enroll::enroll)
+                .layer(DefaultBodyLimit::max(verify_body_limit))
+                .route_layer(middleware::from_fn_with_state(
+                    Arc::clone(&state.verify_limiter),
+                    ratelimit::limit_verify,
+                )),
+        )
+        .route("/enroll/token", post(enroll::create_token))
+        .route("/enroll/token", get(enroll::list_tokens))
+        .route("/enroll/token/revoke", post(enroll::revoke_token))
         .route("/key/add", post(add_key))
         .route("/key/remove", post(remove_key))
+        .route("/key/list", get(list_keys))
         .route("/status", get(status::status))
         .route("/status/host_by_key", get(status::hosts_by_key))
         .route("/host/remove", post(host::remove_host))
         .route("/host/rename", post(host::rename_host))
+        .route("/host/placeholder", post(host::create_placeholder))
+        .route("/host/placeholder", get(host::list_placeholders))
+        .route("/host/placeholder/remove", post(host::remove_placeholder))
+        .route("/host/tags", post(host::set_tags))
+        .route("/host/select", post(host::select_hosts))
+        .route("/host/maintenance", post(host::set_maintenance))
+        .route("/host/maintenance", get(host::group_maintenance))
+        .route("/host/retry", post(host::retry_deployment))
         .route("/system/detach", post(detach::detach_host))
         .route("/system/detach/permission", get(detach::is_detach_allowed))
         .route("/detach/permission", post(detach::set_detach_permission))
         .route("/detach/permission", get(detach::is_detach_global_allowed))
+        .route("/audit", post(audit))
+        .route("/rollout", get(routes::rollout::rollouts))
+        .route("/rollout/pause", post(routes::rollout::pause))
+        .route("/rollout/resume", post(routes::rollout::resume))
+        .route("/rollout/rollback", post(routes::rollout::rollback))
+        .route("/metrics", get(metrics::metrics))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::clone(&state.metrics),
+            metrics::track,
+        ))
         .with_state(state)
 }
 
 #[expect(
     clippy::expect_used,
-    clippy::infinite_loop,
-    reason = "Save state as long as the server is running"
+    clippy::print_stdout,
+    reason = "Migrate is a CLI command"
 )]
-async fn save_state(state: &Arc<RwLock<AppState>>) {
-    let state_location = env::var("YEET_STATE").unwrap_or("state.json".to_owned());
+fn migrate(store: &mut dyn StateStore, stored: Option<Value>, dry_run: bool) {
+    let Some(stored) = stored else {
+        println!("No state found - nothing to migrate");
+        return;
+    };
 
-    let mut interval = interval(Duration::from_millis(500));
-    let file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(false)
-        .open(state_location)
-        .expect("Could not open state.json");
+    let mut migrated = stored.clone();
+    let applied = migration::migrate(&mut migrated).expect("Could not migrate state");
+    if applied.is_empty() {
+        println!("State is already at schema version {SCHEMA_VERSION}");
+        return;
+    }
+
+    for migration in applied {
+        println!("{migration}");
+    }
+    for change in migration::diff(&stored, &migrated) {
+        println!("  {change}");
+    }
+
+    // Make sure the migrated state can actually be read before persisting it
+    serde_json::from_value::<AppState>(migrated.clone())
+        .expect("Migrated state does not match the current schema - missing migration");
 
-    let mut hash = 0;
+    if dry_run {
+        println!("Dry run - state was not modified");
+    } else {
+        store.save(&migrated).expect("Could not persist state");
+        println!("State migrated to schema version {SCHEMA_VERSION}");
+    }
+}
+
+/// Save state as long as the server is running and one last time on shutdown
+#[expect(clippy::expect_used, reason = "Losing state is not an option")]
+async fn save_state(
+    state: Arc<RwLock<AppState>>,
+    mut store: Box<dyn StateStore>,
+    save_interval: Duration,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut interval = interval(save_interval);
 
     loop {
-        interval.tick().await;
-        let state = state.read();
-        let data = serde_json::to_vec_pretty(&*state).expect("Could not serialize state");
-        let mut hasher = DefaultHasher::new();
-        data.hash(&mut hasher);
-
-        if hash != hasher.finish() {
-            hash = hasher.finish();
-            file.set_len(0).expect("Could not truncate file");
-            file.write_all_at(&data, 0)
-                .expect("Could not write to file");
+        let stop = tokio::select! {
+            _ = interval.tick() => false,
+            _ = &mut shutdown => true,
+        };
+
+        // Only hold the lock while serializing - the backend can take its time to write
+        let data = serde_json::to_value(&*state.read()).expect("Could not serialize state");
+        store.save(&data).expect("Could not persist state");
+
+        if stop {
+            break;
         }
     }
 }
@@ -0,0 +1,339 @@
+//! Prometheus metrics served on `/metrics`.
+//!
+//! The endpoint is disabled unless `metrics_token` is configured - the series expose the
+//! hostnames. Prometheus has to send the token as `Authorization: Bearer <token>`.
+//! Request counters and the signature failures are collected while the server runs. The
+//! host gauges are computed from the [`AppState`] on every scrape.
+//! The output follows the Prometheus text exposition format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use jiff::Zoned;
+use parking_lot::{Mutex, RwLock};
+use subtle::ConstantTimeEq as _;
+
+use crate::state::AppState;
+
+/// Upper bounds in seconds of the request duration histogram
+const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 60.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// Count per bucket of [`BUCKETS`] - not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(bucket) = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .and_then(|bucket| self.buckets.get_mut(bucket))
+        {
+            *bucket += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// (method, route, status) -> count
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    /// (method, route) -> durations
+    durations: Mutex<BTreeMap<(String, String), Histogram>>,
+    signature_failures: AtomicU64,
+    /// Bearer token required to scrape. `/metrics` is disabled without
+    token: Option<String>,
+}
+
+impl Metrics {
+    pub fn new(token: Option<String>) -> Self {
+        Self {
+            token,
+            ..Self::default()
+        }
+    }
+
+    pub fn signature_failed(&self) {
+        self.signature_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn observe(&self, method: String, route: String, status: u16, seconds: f64) {
+        *self
+            .requests
+            .lock()
+            .entry((method.clone(), route.clone(), status))
+            .or_default() += 1;
+        self.durations
+            .lock()
+            .entry((method, route))
+            .or_default()
+            .observe(seconds);
+    }
+
+    /// Whether the request carries the configured bearer token
+    fn authorized(&self, headers: &HeaderMap) -> bool {
+        let Some(token) = &self.token else {
+            return false;
+        };
+        headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|given| bool::from(given.as_bytes().ct_eq(token.as_bytes())))
+    }
+
+    fn render(&self, state: &AppState) -> String {
+        let mut out = String::new();
+        let now = Zoned::now();
+
+        let mut by_state = BTreeMap::from([("detached", 0), ("not_set", 0), ("provisioned", 0)]);
+        let mut outdated = 0_u64;
+        for host in state.hosts() {
+            let provision_state = match host.provision_state {
+                api::ProvisionState::NotSet => "not_set",
+                api::ProvisionState::Detached(_) => "detached",
+                api::ProvisionState::Provisioned(_) => "provisioned",
+            };
+            *by_state.entry(provision_state).or_default() += 1_u64;
+            if host
+                .provision_state
+                .store_path()
+                .is_some_and(|store_path| store_path != host.latest_store_path())
+            {
+                outdated += 1;
+            }
+        }
+
+        header(&mut out, "yeet_hosts", "gauge", "Hosts by provision state");
+        for (provision_state, count) in by_state {
+            let _ = writeln!(out, "yeet_hosts{{state=\"{provision_state}\"}} {count}");
+        }
+
+        header(
+            &mut out,
+            "yeet_hosts_outdated",
+            "gauge",
+            "Provisioned hosts which do not run their provisioned version yet",
+        );
+        let _ = writeln!(out, "yeet_hosts_outdated {outdated}");
+
+        header(
+            &mut out,
+            "yeet_host_last_ping_seconds",
+            "gauge",
+            "Seconds since the host last checked in",
+        );
+        let mut hosts: Vec<&api::Host> = state.hosts().collect();
+        hosts.sort_by(|a, b| a.name.cmp(&b.name));
+        for host in hosts {
+            let age = now.duration_since(&host.last_ping).as_secs_f64();
+            let _ = writeln!(
+                out,
+                "yeet_host_last_ping_seconds{{host=\"{}\"}} {age}",
+                escape(&host.name)
+            );
+        }
+
+        header(
+            &mut out,
+            "yeet_verification_attempts_pending",
+            "gauge",
+            "Verification attempts waiting for `yeet approve`",
+        );
+        let _ = writeln!(
+            out,
+            "yeet_verification_attempts_pending {}",
+            state.pending_verifications()
+        );
+
+        header(
+            &mut out,
+            "yeet_http_requests_total",
+            "counter",
+            "Handled requests per route and status",
+        );
+        for ((method, route, status), count) in &*self.requests.lock() {
+            let _ = writeln!(
+                out,
+                "yeet_http_requests_total{{method=\"{method}\",route=\"{}\",status=\"{status}\"}} {count}",
+                escape(route)
+            );
+        }
+
+        header(
+            &mut out,
+            "yeet_http_request_duration_seconds",
+            "histogram",
+            "Time to handle a request per route",
+        );
+        for ((method, route), histogram) in &*self.durations.lock() {
+            let labels = format!("method=\"{method}\",route=\"{}\"", escape(route));
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "yeet_http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "yeet_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "yeet_http_request_duration_seconds_sum{{{labels}}} {}",
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "yeet_http_request_duration_seconds_count{{{labels}}} {}",
+                histogram.count
+            );
+        }
+
+        header(
+            &mut out,
+            "yeet_signature_failures_total",
+            "counter",
+            "Requests rejected because their HTTP signature could not be verified",
+        );
+        let _ = writeln!(
+            out,
+            "yeet_signature_failures_total {}",
+            self.signature_failures.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Middleware counting requests and their duration per matched route
+pub async fn track(State(metrics): State<Arc<Metrics>>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    // Unmatched requests are grouped so that random paths cannot blow up the label set
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_owned(), |path| path.as_str().to_owned());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metrics.observe(
+        method,
+        route,
+        response.status().as_u16(),
+        start.elapsed().as_secs_f64(),
+    );
+    response
+}
+
+/// Only answers with the configured bearer token. Not found if no token is configured
+pub async fn metrics(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(metrics): State<Arc<Metrics>>,
+    headers: HeaderMap,
+) -> Response {
+    if metrics.token.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    if !metrics.authorized(&headers) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response();
+    }
+    let body = metrics.render(&state.read_arc());
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body).into_response()
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use axum::http::{HeaderMap, HeaderValue, header};
+
+    use crate::{
+        metrics::{Metrics, escape},
+        state::AppState,
+    };
+
+    #[test]
+    fn test_histogram_is_cumulative() {
+        let metrics = Metrics::default();
+        metrics.observe("POST".to_owned(), "/system/check".to_owned(), 200, 0.003);
+        metrics.observe("POST".to_owned(), "/system/check".to_owned(), 200, 0.2);
+        metrics.observe("POST".to_owned(), "/system/check".to_owned(), 403, 120.0);
+        metrics.signature_failed();
+
+        let out = metrics.render(&AppState::default());
+        let labels = "method=\"POST\",route=\"/system/check\"";
+        assert!(out.contains(&format!(
+            "yeet_http_request_duration_seconds_bucket{{{labels},le=\"0.005\"}} 1"
+        )));
+        assert!(out.contains(&format!(
+            "yeet_http_request_duration_seconds_bucket{{{labels},le=\"60\"}} 2"
+        )));
+        assert!(out.contains(&format!(
+            "yeet_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} 3"
+        )));
+        assert!(out.contains(&format!(
+            "yeet_http_requests_total{{{labels},status=\"403\"}} 1"
+        )));
+        assert!(out.contains("yeet_signature_failures_total 1"));
+        assert!(out.contains("yeet_hosts{state=\"provisioned\"} 0"));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let bearer = |value: &'static str| {
+            HeaderMap::from_iter([(header::AUTHORIZATION, HeaderValue::from_static(value))])
+        };
+
+        let metrics = Metrics::new(Some("secret".to_owned()));
+        assert!(metrics.authorized(&bearer("Bearer secret")));
+        assert!(!metrics.authorized(&bearer("Bearer secre")));
+        assert!(!metrics.authorized(&bearer("Basic secret")));
+        assert!(!metrics.authorized(&HeaderMap::new()));
+
+        // Disabled without a token
+        assert!(!Metrics::default().authorized(&bearer("Bearer ")));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,294 @@
+//! Schema migrations of the persisted [`AppState`](crate::state::AppState)
+//!
+//! Migrations operate on the raw JSON before it gets deserialized. This way a migration can
+//! reshape the state without having to keep old versions of the structs around.
+//! Every change to the shape of the state which serde cannot handle on its own
+//! (e.g. new fields with `#[serde(default)]`) requires a new entry in [`MIGRATIONS`]
+//! and bumping [`SCHEMA_VERSION`].
+
+use std::fmt::Display;
+
+use jiff::Zoned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use thiserror::Error;
+
+/// Schema version of the state this version of yeetd reads and writes
+pub const SCHEMA_VERSION: u32 = 3;
+
+/// Name of the field in the persisted state holding the schema version
+const VERSION_FIELD: &str = "schema_version";
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error("State has to be a JSON object")]
+    NotAnObject,
+
+    #[error("`{VERSION_FIELD}` is not a valid version")]
+    InvalidVersion,
+
+    #[error(
+        "State has schema version {0} but this yeetd only supports up to {SCHEMA_VERSION} - did you downgrade?"
+    )]
+    NewerSchema(u32),
+
+    #[error("No migration found from schema version {0}")]
+    MissingMigration(u32),
+
+    #[error("Migration from schema version {version} failed: {reason}")]
+    Failed { version: u32, reason: String },
+}
+
+/// Schema version stored in the state. A fresh state always starts at the current version.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(transparent)]
+pub struct SchemaVersion(u32);
+
+impl Default for SchemaVersion {
+    fn default() -> Self {
+        Self(SCHEMA_VERSION)
+    }
+}
+
+type MigrationFn = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+pub struct Migration {
+    /// Schema version this migration upgrades from. Upgrades always go to `from + 1`
+    from: u32,
+    description: &'static str,
+    migrate: MigrationFn,
+}
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{} -> v{}: {}", self.from, self.from + 1, self.description)
+    }
+}
+
+/// All migrations ordered by the version they upgrade from
+static MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "Introduce schema versioning",
+        migrate: |_state| Ok(()),
+    },
+    Migration {
+        from: 1,
+        description: "Store the commit with each entry of the version history",
+        migrate: version_history_objects,
+    },
+    Migration {
+        from: 2,
+        description: "Keep labels, creation and last use of admin and build keys",
+        migrate: key_inventory,
+    },
+];
+
+/// `version_history` entries change from `[store_path, time]` to
+/// `{ store_path, since, commit }`. The commit of old entries is unknown
+fn version_history_objects(state: &mut Map<String, Value>) -> Result<(), String> {
+    let Some(hosts) = state.get_mut("hosts") else {
+        return Ok(());
+    };
+    let hosts = hosts.as_object_mut().ok_or("`hosts` is not an object")?;
+
+    for (hostname, host) in hosts {
+        let Some(history) = host
+            .get_mut("version_history")
+            .and_then(Value::as_array_mut)
+        else {
+            continue;
+        };
+
+        for entry in history {
+            let [store_path, since] = entry
+                .as_array()
+                .and_then(|entry| <&[Value; 2]>::try_from(entry.as_slice()).ok())
+                .ok_or_else(|| format!("Invalid version history entry of {hostname}: {entry}"))?
+                .clone();
+            *entry = json!({ "store_path": store_path, "since": since, "commit": null });
+        }
+    }
+    Ok(())
+}
+
+/// `admin_credentials` and `build_machines_credentials` become one `credentials` list of
+/// `{ key, level, label, created, last_used, expires, scope }`. Existing keys are created now.
+/// A key in both lists keeps its admin level
+fn key_inventory(state: &mut Map<String, Value>) -> Result<(), String> {
+    let created = Zoned::now().to_string();
+    let mut credentials: Vec<Value> = Vec::new();
+
+    for (field, level) in [
+        ("admin_credentials", "Admin"),
+        ("build_machines_credentials", "Build"),
+    ] {
+        let keys = match state.remove(field) {
+            None | Some(Value::Null) => continue,
+            Some(Value::Array(keys)) => keys,
+            Some(_) => return Err(format!("`{field}` is not a list")),
+        };
+        for key in keys {
+            if credentials
+                .iter()
+                .any(|credential| credential["key"] == key)
+            {
+                continue;
+            }
+            credentials.push(json!({
+                "key": key,
+                "level": level,
+                "label": "",
+                "created": created,
+                "last_used": null,
+                "expires": null,
+                "scope": null,
+            }));
+        }
+    }
+
+    state.insert("credentials".to_owned(), Value::Array(credentials));
+    Ok(())
+}
+
+/// Brings the state up to [`SCHEMA_VERSION`] and returns the migrations which were applied.
+/// A state without a version is considered version 0.
+pub fn migrate(state: &mut Value) -> Result<Vec<&'static Migration>, MigrationError> {
+    let Value::Object(state) = state else {
+        return Err(MigrationError::NotAnObject);
+    };
+
+    let mut version = match state.get(VERSION_FIELD) {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or(MigrationError::InvalidVersion)?,
+    };
+
+    if version > SCHEMA_VERSION {
+        return Err(MigrationError::NewerSchema(version));
+    }
+
+    let mut applied = Vec::new();
+    while version < SCHEMA_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or(MigrationError::MissingMigration(version))?;
+
+        (migration.migrate)(state)
+            .map_err(|reason| MigrationError::Failed { version, reason })?;
+
+        version += 1;
+        state.insert(VERSION_FIELD.to_owned(), Value::from(version));
+        applied.push(migration);
+    }
+
+    Ok(applied)
+}
+
+/// Lists the changes between two states. One line per changed value with its JSON path
+pub fn diff(old: &Value, new: &Value) -> Vec<String> {
+    let mut changes = Vec::new();
+    diff_at("", old, new, &mut changes);
+    changes
+}
+
+fn diff_at(path: &str, old: &Value, new: &Value, changes: &mut Vec<String>) {
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old_value) in old {
+                let path = format!("{path}/{key}");
+                match new.get(key) {
+                    Some(new_value) => diff_at(&path, old_value, new_value, changes),
+                    None => changes.push(format!("- {path}")),
+                }
+            }
+            for (key, new_value) in new {
+                if !old.contains_key(key) {
+                    changes.push(format!("+ {path}/{key}: {new_value}"));
+                }
+            }
+        }
+        (old, new) if old != new => changes.push(format!("~ {path}: {old} -> {new}")),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test_migration {
+    use serde_json::json;
+
+    use crate::migration::{MigrationError, SCHEMA_VERSION, diff, migrate};
+
+    #[test]
+    fn unversioned_state_is_migrated() {
+        let mut state = json!({ "hosts": {}, "detach_allowed": false });
+        let applied = migrate(&mut state).unwrap();
+
+        assert_eq!(applied.len(), usize::try_from(SCHEMA_VERSION).unwrap());
+        assert_eq!(state["schema_version"], json!(SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn current_state_is_untouched() {
+        let mut state = json!({ "schema_version": SCHEMA_VERSION, "hosts": {} });
+        let before = state.clone();
+
+        assert!(migrate(&mut state).unwrap().is_empty());
+        assert!(diff(&before, &state).is_empty());
+    }
+
+    #[test]
+    fn version_history_becomes_objects() {
+        let mut state = json!({
+            "schema_version": 1,
+            "hosts": { "web": { "version_history": [["/nix/store/a", "2025-01-01T00:00:00+00:00[UTC]"]] } }
+        });
+        migrate(&mut state).unwrap();
+
+        assert_eq!(
+            state["hosts"]["web"]["version_history"],
+            json!([{ "store_path": "/nix/store/a", "since": "2025-01-01T00:00:00+00:00[UTC]", "commit": null }])
+        );
+    }
+
+    #[test]
+    fn credentials_are_merged() {
+        let mut state = json!({
+            "schema_version": 2,
+            "admin_credentials": ["admin"],
+            "build_machines_credentials": ["build", "admin"]
+        });
+        migrate(&mut state).unwrap();
+
+        let credentials = state["credentials"].as_array().unwrap();
+        assert_eq!(credentials.len(), 2);
+        assert_eq!(credentials[0]["key"], json!("admin"));
+        assert_eq!(credentials[0]["level"], json!("Admin"));
+        assert_eq!(credentials[1]["key"], json!("build"));
+        assert_eq!(credentials[1]["level"], json!("Build"));
+        assert!(state.get("admin_credentials").is_none());
+    }
+
+    #[test]
+    fn newer_state_is_rejected() {
+        let mut state = json!({ "schema_version": SCHEMA_VERSION + 1 });
+        assert!(matches!(
+            migrate(&mut state),
+            Err(MigrationError::NewerSchema(_))
+        ));
+    }
+
+    #[test]
+    fn diff_lists_changed_paths() {
+        let old = json!({ "a": 1, "b": { "c": true }, "d": 0 });
+        let new = json!({ "a": 2, "b": { "c": true, "e": "x" } });
+
+        assert_eq!(
+            diff(&old, &new),
+            vec!["~ /a: 1 -> 2", "+ /b/e: \"x\"", "- /d"]
+        );
+    }
+}
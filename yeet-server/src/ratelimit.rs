@@ -0,0 +1,209 @@
+//! Abuse protection of `POST /system/verify` and `POST /system/enroll` which anyone can call.
+//!
+//! Without it a single client could fill all verification slots and lock out real enrollments
+//! or guess enrollment tokens.
+//! Requests are only accepted from the allowed networks and each source may only try a few
+//! times per minute. A source is an IPv4 address or an IPv6 /64 since IPv6 clients usually
+//! control a whole /64. Behind a reverse proxy every request has the address of the proxy - limit
+//! the requests in the proxy instead.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse as _, Response},
+};
+use axum_thiserror::ErrorStatus;
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use thiserror::Error;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Sources remembered at most. The source with the oldest window is forgotten to make room so
+/// that nobody can lock out new sources by filling the table
+const MAX_SOURCES: usize = 10_000;
+
+#[derive(Error, Debug, ErrorStatus, PartialEq, Eq)]
+pub enum RateLimitError {
+    #[error("{0} is not allowed to enroll")]
+    #[status(StatusCode::FORBIDDEN)]
+    NotAllowed(IpAddr),
+
+    #[error("Too many enrollment attempts from {0} - try again in a minute")]
+    #[status(StatusCode::TOO_MANY_REQUESTS)]
+    TooManyRequests(IpAddr),
+}
+
+pub struct VerifyLimiter {
+    /// Attempts per source address and minute
+    rate: u32,
+    /// Networks which may enroll. Everyone if empty
+    allow: Vec<IpNet>,
+    max_sources: usize,
+    /// Source -> (start of the window, attempts in the window). See [`source_bucket`]
+    sources: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+/// The address attempts are counted for. IPv6 addresses are cut to their /64
+fn source_bucket(source: IpAddr) -> IpAddr {
+    match source {
+        IpAddr::V4(_) => source,
+        IpAddr::V6(address) => IpAddr::V6(Ipv6Addr::from_bits(
+            address.to_bits() & !u128::from(u64::MAX),
+        )),
+    }
+}
+
+impl VerifyLimiter {
+    pub fn new(rate: u32, allow: Vec<IpNet>) -> Self {
+        Self {
+            rate,
+            allow,
+            max_sources: MAX_SOURCES,
+            sources: Mutex::default(),
+        }
+    }
+
+    pub fn check(&self, source: IpAddr) -> Result<(), RateLimitError> {
+        self.check_at(source, Instant::now())
+    }
+
+    fn check_at(&self, source: IpAddr, now: Instant) -> Result<(), RateLimitError> {
+        // `::ffff:10.0.0.1` has to match `10.0.0.0/8` on dual stack listeners
+        let source = source.to_canonical();
+        if !self.allow.is_empty() && !self.allow.iter().any(|net| net.contains(&source)) {
+            return Err(RateLimitError::NotAllowed(source));
+        }
+
+        let bucket = source_bucket(source);
+        let mut sources = self.sources.lock();
+        sources.retain(|_bucket, (start, _attempts)| now.duration_since(*start) < WINDOW);
+        if sources.len() >= self.max_sources && !sources.contains_key(&bucket) {
+            let oldest = sources
+                .iter()
+                .min_by_key(|(_bucket, (start, _attempts))| *start)
+                .map(|(bucket, _window)| *bucket);
+            if let Some(oldest) = oldest {
+                sources.remove(&oldest);
+            }
+        }
+
+        let (_start, attempts) = sources.entry(bucket).or_insert((now, 0));
+        if *attempts >= self.rate {
+            return Err(RateLimitError::TooManyRequests(source));
+        }
+        *attempts += 1;
+        Ok(())
+    }
+}
+
+/// Middleware of `POST /system/verify` and `POST /system/enroll`. Both share the limit
+pub async fn limit_verify(
+    State(limiter): State<Arc<VerifyLimiter>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limiter.check(address.ip()) {
+        Ok(()) => next.run(request).await,
+        Err(err) => err.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod test_ratelimit {
+    use std::{
+        net::IpAddr,
+        time::{Duration, Instant},
+    };
+
+    use crate::ratelimit::{RateLimitError, VerifyLimiter};
+
+    #[test]
+    fn test_rate() {
+        let limiter = VerifyLimiter::new(2, Vec::new());
+        let source: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at(source, now), Ok(()));
+        assert_eq!(limiter.check_at(source, now), Ok(()));
+        assert_eq!(
+            limiter.check_at(source, now),
+            Err(RateLimitError::TooManyRequests(source))
+        );
+        assert_eq!(limiter.check_at(other, now), Ok(()));
+        assert_eq!(
+            limiter.check_at(source, now + Duration::from_secs(61)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_ipv6_prefix() {
+        let limiter = VerifyLimiter::new(1, Vec::new());
+        let now = Instant::now();
+        let source: IpAddr = "2001:db8::1".parse().unwrap();
+        let same_prefix: IpAddr = "2001:db8::ffff:2".parse().unwrap();
+
+        assert_eq!(limiter.check_at(source, now), Ok(()));
+        assert_eq!(
+            limiter.check_at(same_prefix, now),
+            Err(RateLimitError::TooManyRequests(same_prefix))
+        );
+        assert_eq!(
+            limiter.check_at("2001:db8:0:1::1".parse().unwrap(), now),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_full_table_evicts_oldest() {
+        let limiter = VerifyLimiter {
+            max_sources: 2,
+            ..VerifyLimiter::new(1, Vec::new())
+        };
+        let now = Instant::now();
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(limiter.check_at(first, now), Ok(()));
+        assert_eq!(
+            limiter.check_at("192.0.2.2".parse().unwrap(), now + Duration::from_secs(1)),
+            Ok(())
+        );
+        // A new source still gets in and the oldest one is forgotten
+        assert_eq!(
+            limiter.check_at("192.0.2.3".parse().unwrap(), now + Duration::from_secs(2)),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.check_at(first, now + Duration::from_secs(3)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_allow() {
+        let limiter = VerifyLimiter::new(10, vec!["10.0.0.0/8".parse().unwrap()]);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check_at("10.1.2.3".parse().unwrap(), now), Ok(()));
+        assert_eq!(
+            limiter.check_at("::ffff:10.1.2.3".parse().unwrap(), now),
+            Ok(())
+        );
+        let outside: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(
+            limiter.check_at(outside, now),
+            Err(RateLimitError::NotAllowed(outside))
+        );
+    }
+}
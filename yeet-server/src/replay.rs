@@ -0,0 +1,195 @@
+//! Replay protection for HTTP message signatures.
+//!
+//! A signature is only accepted if its `created` parameter is within the configured skew of the
+//! server time and its `expires` parameter (if set) has not passed yet. Within that window every
+//! nonce is accepted only once. Nonces are remembered until their signature could no longer be
+//! accepted anyway, so the cache only has to hold the nonces of the last `2 * skew` seconds.
+
+use std::collections::{HashSet, VecDeque};
+
+use axum::http::StatusCode;
+use httpsig_hyper::prelude::HttpSignatureParams;
+use jiff::Timestamp;
+use parking_lot::Mutex;
+use thiserror::Error;
+
+use crate::config::ReplayLimits;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ReplayError {
+    #[error("Signature has no `created` parameter")]
+    MissingCreated,
+
+    #[error("Signature was created {0}s away from the server time - check the clock of the client")]
+    Skewed(i64),
+
+    #[error("Signature expired")]
+    Expired,
+
+    #[error("Signature has no `nonce` parameter")]
+    MissingNonce,
+
+    #[error("Signature nonce was already used")]
+    Replayed,
+
+    #[error("Too many requests to remember their nonces - try again later")]
+    CacheFull,
+}
+
+impl ReplayError {
+    /// Status of the rejection. Everything but a full cache is the fault of the client
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ReplayError::CacheFull => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+#[derive(Default)]
+struct NonceCache {
+    seen: HashSet<String>,
+    /// Nonces with the unix time after which they can be forgotten, oldest first
+    expiry: VecDeque<(i64, String)>,
+}
+
+pub struct ReplayGuard {
+    limits: ReplayLimits,
+    nonces: Mutex<NonceCache>,
+}
+
+impl ReplayGuard {
+    pub fn new(limits: ReplayLimits) -> Self {
+        Self {
+            limits,
+            nonces: Mutex::default(),
+        }
+    }
+
+    /// Check a signature which was already verified
+    pub fn check(&self, params: &HttpSignatureParams) -> Result<(), ReplayError> {
+        self.check_at(params, Timestamp::now().as_second())
+    }
+
+    fn check_at(&self, params: &HttpSignatureParams, now: i64) -> Result<(), ReplayError> {
+        let skew = self.limits.signature_skew;
+
+        let created = params
+            .created
+            .and_then(|created| i64::try_from(created).ok())
+            .ok_or(ReplayError::MissingCreated)?;
+        let offset = now.saturating_sub(created);
+        if offset.abs() > skew {
+            return Err(ReplayError::Skewed(offset));
+        }
+
+        if let Some(expires) = params.expires
+            && i64::try_from(expires).is_ok_and(|expires| expires < now)
+        {
+            return Err(ReplayError::Expired);
+        }
+
+        let Some(nonce) = &params.nonce else {
+            return if self.limits.require_nonce {
+                Err(ReplayError::MissingNonce)
+            } else {
+                Ok(())
+            };
+        };
+
+        let mut cache = self.nonces.lock();
+        while cache
+            .expiry
+            .front()
+            .is_some_and(|(forget_at, _nonce)| *forget_at < now)
+        {
+            if let Some((_forget_at, nonce)) = cache.expiry.pop_front() {
+                cache.seen.remove(&nonce);
+            }
+        }
+
+        if cache.seen.contains(nonce) {
+            return Err(ReplayError::Replayed);
+        }
+        if cache.seen.len() >= self.limits.nonce_cache_size {
+            return Err(ReplayError::CacheFull);
+        }
+
+        cache.seen.insert(nonce.clone());
+        // After this the `created` check rejects the signature on its own
+        cache
+            .expiry
+            .push_back((created.saturating_add(skew), nonce.clone()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_replay {
+    use httpsig_hyper::prelude::{HttpSignatureParams, message_component};
+
+    use crate::{
+        config::ReplayLimits,
+        replay::{ReplayError, ReplayGuard},
+    };
+
+    fn params(created: u64, nonce: Option<&str>) -> HttpSignatureParams {
+        let components = [message_component::HttpMessageComponentId::try_from("@method").unwrap()];
+        let mut params = HttpSignatureParams::try_new(&components).unwrap();
+        params.created = Some(created);
+        params.nonce = nonce.map(str::to_owned);
+        params
+    }
+
+    fn guard() -> ReplayGuard {
+        ReplayGuard::new(ReplayLimits {
+            signature_skew: 60,
+            require_nonce: true,
+            nonce_cache_size: 2,
+        })
+    }
+
+    #[test]
+    fn test_skew() {
+        let guard = guard();
+        assert_eq!(
+            guard.check_at(&params(1000, Some("a")), 1100),
+            Err(ReplayError::Skewed(100))
+        );
+        assert_eq!(
+            guard.check_at(&params(1100, Some("a")), 1000),
+            Err(ReplayError::Skewed(-100))
+        );
+        assert_eq!(guard.check_at(&params(1000, Some("a")), 1030), Ok(()));
+    }
+
+    #[test]
+    fn test_nonce() {
+        let guard = guard();
+        assert_eq!(
+            guard.check_at(&params(1000, None), 1000),
+            Err(ReplayError::MissingNonce)
+        );
+        assert_eq!(guard.check_at(&params(1000, Some("a")), 1000), Ok(()));
+        assert_eq!(
+            guard.check_at(&params(1000, Some("a")), 1001),
+            Err(ReplayError::Replayed)
+        );
+        assert_eq!(guard.check_at(&params(1000, Some("b")), 1001), Ok(()));
+        assert_eq!(
+            guard.check_at(&params(1000, Some("c")), 1001),
+            Err(ReplayError::CacheFull)
+        );
+        // `a` and `b` are forgotten once their signatures are too old anyway
+        assert_eq!(guard.check_at(&params(1070, Some("c")), 1070), Ok(()));
+    }
+
+    #[test]
+    fn test_expires() {
+        let guard = guard();
+        let mut expired = params(1000, Some("a"));
+        expired.expires = Some(1010);
+        assert_eq!(guard.check_at(&expired, 1010), Ok(()));
+        assert_eq!(guard.check_at(&expired, 1011), Err(ReplayError::Expired));
+    }
+}
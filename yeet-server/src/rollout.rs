@@ -0,0 +1,101 @@
+//! Staged rollouts ship a version in waves.
+//!
+//! Each host gets a bucket from [`api::rollout_bucket`]. A wave with percentage `p` contains
+//! every host with a bucket below `p`. The next wave only starts once all hosts of the
+//! previous waves run the new version for at least the soak time of the rollout.
+
+use std::collections::HashMap;
+
+use jiff::Zoned;
+
+/// Checks that the waves are increasing, within 1..=100 and end with 100
+pub fn validate(plan: &api::RolloutPlan) -> Result<(), String> {
+    if plan.waves.last() != Some(&100) {
+        return Err("The last wave has to be 100%".to_owned());
+    }
+    if plan.waves.first() == Some(&0) {
+        return Err("A wave cannot be 0%".to_owned());
+    }
+    if plan.waves.windows(2).any(|waves| waves[0] >= waves[1]) {
+        return Err("Waves have to be increasing".to_owned());
+    }
+    if plan.soak.is_negative() {
+        return Err("Soak time cannot be negative".to_owned());
+    }
+    Ok(())
+}
+
+/// Is the host part of the current or any previous wave
+pub fn is_included(rollout: &api::Rollout, hostname: &str) -> bool {
+    api::rollout_bucket(hostname) < rollout.plan.waves[rollout.wave]
+}
+
+/// Hosts which are part of the current wave but were not part of the previous one
+pub fn current_wave(rollout: &api::Rollout) -> impl Iterator<Item = (&String, &api::RemoteStorePath)> {
+    let lower = rollout
+        .wave
+        .checked_sub(1)
+        .map_or(0, |previous| rollout.plan.waves[previous]);
+    let upper = rollout.plan.waves[rollout.wave];
+
+    rollout.hosts.iter().filter(move |(hostname, _version)| {
+        let bucket = api::rollout_bucket(hostname);
+        lower <= bucket && bucket < upper
+    })
+}
+
+/// True if every included host has been running its new version for the soak time.
+/// Detached or removed hosts do not report a version and are therefore ignored.
+pub fn is_soaked(rollout: &api::Rollout, hosts: &HashMap<String, api::Host>, now: &Zoned) -> bool {
+    if now.duration_since(&rollout.wave_started) < rollout.plan.soak {
+        return false;
+    }
+
+    rollout
+        .hosts
+        .iter()
+        .filter(|(hostname, _version)| is_included(rollout, hostname))
+        .filter_map(|(hostname, version)| Some((hosts.get(hostname)?, version)))
+        .filter(|(host, _version)| !host.is_detached())
+        .all(|(host, version)| {
+            host.version_history.last().is_some_and(|latest| {
+                latest.store_path == version.store_path
+                    && now.duration_since(&latest.since) >= rollout.plan.soak
+            })
+        })
+}
+
+#[cfg(test)]
+mod test_rollout {
+    use jiff::SignedDuration;
+
+    use crate::rollout::validate;
+
+    fn plan(waves: &[u8]) -> api::RolloutPlan {
+        api::RolloutPlan {
+            waves: waves.to_vec(),
+            soak: SignedDuration::from_mins(30),
+        }
+    }
+
+    #[test]
+    fn valid_plans() {
+        assert!(validate(&plan(&[100])).is_ok());
+        assert!(validate(&plan(&[5, 25, 100])).is_ok());
+    }
+
+    #[test]
+    fn invalid_plans() {
+        assert!(validate(&plan(&[])).is_err());
+        assert!(validate(&plan(&[5, 25])).is_err());
+        assert!(validate(&plan(&[0, 100])).is_err());
+        assert!(validate(&plan(&[25, 5, 100])).is_err());
+        assert!(validate(&plan(&[50, 50, 100])).is_err());
+    }
+
+    #[test]
+    fn buckets_are_stable() {
+        assert_eq!(api::rollout_bucket("web-1"), api::rollout_bucket("web-1"));
+        assert!(api::rollout_bucket("web-1") < 100);
+    }
+}
@@ -0,0 +1,22 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State};
+use parking_lot::RwLock;
+
+use crate::{
+    audit::AuditLog,
+    httpsig::{HttpSig, VerifiedJson},
+    state::{AppState, StateError},
+};
+
+/// Query the audit log. Filters are sent as signed json instead of query parameters
+/// because query parameters are not covered by the signature
+pub async fn audit(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(query): VerifiedJson<api::AuditQuery>,
+) -> Result<Json<Vec<api::AuditEntry>>, StateError> {
+    state.read_arc().authorize(&key, api::Permission::Admin)?;
+    Ok(Json(audit.query(&query)?))
+}
@@ -0,0 +1,21 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode};
+use parking_lot::RwLock;
+
+use crate::{
+    AppState,
+    httpsig::{HttpSig, VerifiedJson},
+    state::StateError,
+};
+
+/// The agent reports how a `SwitchTo` went
+pub async fn report_deployment(
+    State(state): State<Arc<RwLock<AppState>>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(report): VerifiedJson<api::DeploymentReport>,
+) -> Result<StatusCode, StateError> {
+    let mut state = state.write_arc();
+    state.report_deployment(&key, report)?;
+    Ok(StatusCode::OK)
+}
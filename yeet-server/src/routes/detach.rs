@@ -4,6 +4,8 @@ use axum::{Json, extract::State, http::StatusCode};
 use parking_lot::RwLock;
 
 use crate::{
+    audit::AuditLog,
+    events::ProvisionEvents,
     httpsig::{HttpSig, VerifiedJson},
     state::{AppState, StateError},
 };
@@ -23,41 +25,57 @@ pub async fn is_detach_global_allowed(
     HttpSig(key): HttpSig,
 ) -> Result<Json<bool>, StateError> {
     let state = state.read_arc();
-    state.auth_admin(&key)?;
+    state.authorize(&key, api::Permission::Read)?;
     Ok(Json(state.get_global_detach_permission()))
 }
 
 /// Set the detach permission either Global or PerHost. PerHost will always take over the global setting
 pub async fn set_detach_permission(
     State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
     HttpSig(key): HttpSig,
     VerifiedJson(set_detach): VerifiedJson<api::SetDetachPermission>,
 ) -> Result<StatusCode, StateError> {
     let mut state = state.write_arc();
-    state.auth_admin(&key)?;
+    state.authorize(&key, api::Permission::Admin)?;
 
+    let action = api::AuditAction::SetDetachPermission(set_detach.clone());
     match set_detach {
         api::SetDetachPermission::Global(allowed) => state.set_global_detach_permission(allowed),
         api::SetDetachPermission::PerHost(items) => state.set_detach_permissions(items),
     }
 
+    audit.record(&state, &key, action)?;
+
     Ok(StatusCode::OK)
 }
 
-/// Detach either self or another host(requires admin)
+/// Detach either self or another host (requires the operate permission)
 pub async fn detach_host(
     State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    State(events): State<Arc<ProvisionEvents>>,
     HttpSig(key): HttpSig,
     VerifiedJson(detach): VerifiedJson<api::DetachAction>,
 ) -> Result<StatusCode, StateError> {
     let mut state = state.write_arc();
 
+    let action = api::AuditAction::Detach(detach.clone());
     match detach {
         api::DetachAction::DetachSelf => state.detach_self(&key)?,
-        api::DetachAction::DetachHost(hostname) => state.detach_host(&hostname)?,
+        api::DetachAction::DetachHost(hostname) => {
+            state.authorize(&key, api::Permission::Operate)?;
+            state.detach_host(&hostname)?;
+        }
         api::DetachAction::AttachSelf => state.attach_self(&key)?,
-        api::DetachAction::AttachHost(hostname) => state.attach_host(&hostname)?,
+        api::DetachAction::AttachHost(hostname) => {
+            state.authorize(&key, api::Permission::Operate)?;
+            state.attach_host(&hostname)?;
+        }
     }
+    events.notify();
+
+    audit.record(&state, &key, action)?;
 
     Ok(StatusCode::OK)
 }
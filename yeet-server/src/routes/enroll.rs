@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use api::enroll::{Enrollment, EnrollmentToken, TokenRequest};
+use axum::{Json, extract::State, http::StatusCode};
+use parking_lot::RwLock;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    audit::AuditLog,
+    httpsig::{HttpSig, VerifiedJson},
+    state::StateError,
+};
+
+/// Mint an enrollment token. This is the only time the secret of the token is returned
+pub async fn create_token(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(request): VerifiedJson<TokenRequest>,
+) -> Result<Json<EnrollmentToken>, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Admin)?;
+
+    let token = state.create_enrollment_token(request)?;
+    audit.record(
+        &state,
+        &key,
+        api::AuditAction::CreateEnrollmentToken {
+            id: token.id,
+            hostname: token.hostname.clone(),
+            tags: token.tags.clone(),
+            uses: token.uses_left,
+            expires: token.expires.clone(),
+        },
+    )?;
+    Ok(Json(token))
+}
+
+/// Tokens which did not expire yet without their secrets
+pub async fn list_tokens(
+    State(state): State<Arc<RwLock<AppState>>>,
+    HttpSig(key): HttpSig,
+) -> Result<Json<Vec<EnrollmentToken>>, StateError> {
+    let state = state.read_arc();
+    state.authorize(&key, api::Permission::Admin)?;
+    Ok(Json(state.enrollment_tokens()))
+}
+
+pub async fn revoke_token(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(id): VerifiedJson<Uuid>,
+) -> Result<StatusCode, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Admin)?;
+    state.revoke_enrollment_token(id)?;
+    audit.record(&state, &key, api::AuditAction::RevokeEnrollmentToken { id })?;
+    Ok(StatusCode::OK)
+}
+
+/// Unauthenticated like `add_verification_attempt` - the token is the authentication.
+/// Returns the name the host was registered with
+pub async fn enroll(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    Json(enrollment): Json<Enrollment>,
+) -> Result<Json<String>, StateError> {
+    let mut state = state.write_arc();
+    let key = enrollment.key;
+    let (hostname, token) = state.enroll(enrollment)?;
+    audit.record(
+        &state,
+        &key,
+        api::AuditAction::Enroll {
+            token,
+            hostname: hostname.clone(),
+        },
+    )?;
+    Ok(Json(hostname))
+}
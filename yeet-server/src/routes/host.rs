@@ -4,24 +4,73 @@ use axum::{Json, extract::State, http::StatusCode};
 use parking_lot::RwLock;
 
 use crate::{
+    audit::AuditLog,
+    events::ProvisionEvents,
     httpsig::{HttpSig, VerifiedJson},
     state::{AppState, StateError},
 };
 
-/// Host creation is done via the approve command. Now we need functions to delete and rename hosts.
+/// Host creation is done via the approve command. Now we need functions to delete, rename and tag hosts.
+/// Placeholders reserve a name and a version for hosts which did not enroll yet.
 
 pub async fn remove_host(
     State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    State(events): State<Arc<ProvisionEvents>>,
     HttpSig(key): HttpSig,
     VerifiedJson(api::HostRemoveRequest { hostname }): VerifiedJson<api::HostRemoveRequest>,
 ) -> Result<Json<api::Host>, StateError> {
     let mut state = state.write_arc();
-    state.auth_admin(&key)?;
-    Ok(Json(state.remove_host(&hostname)?))
+    state.authorize(&key, api::Permission::Admin)?;
+    let host = state.remove_host(&hostname)?;
+    events.notify();
+    audit.record(&state, &key, api::AuditAction::RemoveHost { hostname })?;
+    Ok(Json(host))
+}
+
+/// Pre-provision a host so that it switches to its version right after it enrolls
+pub async fn create_placeholder(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(request): VerifiedJson<api::PlaceholderRequest>,
+) -> Result<StatusCode, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Admin)?;
+    state.create_placeholder(request.clone())?;
+    audit.record(&state, &key, api::AuditAction::CreatePlaceholder(request))?;
+    Ok(StatusCode::CREATED)
+}
+
+pub async fn list_placeholders(
+    State(state): State<Arc<RwLock<AppState>>>,
+    HttpSig(key): HttpSig,
+) -> Result<Json<Vec<api::PlaceholderHost>>, StateError> {
+    let state = state.read_arc();
+    state.authorize(&key, api::Permission::Read)?;
+    Ok(Json(state.placeholders()))
+}
+
+pub async fn remove_placeholder(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(api::HostRemoveRequest { hostname }): VerifiedJson<api::HostRemoveRequest>,
+) -> Result<StatusCode, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Admin)?;
+    state.remove_placeholder(&hostname)?;
+    audit.record(
+        &state,
+        &key,
+        api::AuditAction::RemovePlaceholder { hostname },
+    )?;
+    Ok(StatusCode::OK)
 }
 
 pub async fn rename_host(
     State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
     HttpSig(key): HttpSig,
     VerifiedJson(api::HostRenameRequest {
         current_name,
@@ -29,7 +78,84 @@ pub async fn rename_host(
     }): VerifiedJson<api::HostRenameRequest>,
 ) -> Result<StatusCode, StateError> {
     let mut state = state.write_arc();
-    state.auth_admin(&key)?;
-    state.rename_host(&current_name, new_name)?;
+    state.authorize(&key, api::Permission::Admin)?;
+    state.rename_host(&current_name, new_name.clone())?;
+    audit.record(
+        &state,
+        &key,
+        api::AuditAction::RenameHost {
+            current_name,
+            new_name,
+        },
+    )?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn set_tags(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(request): VerifiedJson<api::HostTagsRequest>,
+) -> Result<StatusCode, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Admin)?;
+    state.set_tags(&request.hostname, request.set.clone(), &request.remove)?;
+    audit.record(&state, &key, api::AuditAction::SetTags(request))?;
+    Ok(StatusCode::OK)
+}
+
+/// Resolve a tag selector to hostnames. Allowed for build keys so that pipelines
+/// can find out which hosts they have to build. Only hosts in the scope of the key are returned
+pub async fn select_hosts(
+    State(state): State<Arc<RwLock<AppState>>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(selector): VerifiedJson<api::TagSelector>,
+) -> Result<Json<Vec<String>>, StateError> {
+    let state = state.read_arc();
+    state.authorize(&key, api::Permission::Publish)?;
+    let mut hostnames = state.select_hosts(&selector);
+    hostnames.retain(|hostname| state.in_scope(&key, hostname));
+    Ok(Json(hostnames))
+}
+
+/// Set the maintenance windows of a host or of all hosts matching a selector
+pub async fn set_maintenance(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    State(events): State<Arc<ProvisionEvents>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(request): VerifiedJson<api::maintenance::MaintenanceRequest>,
+) -> Result<StatusCode, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Operate)?;
+    state.set_maintenance(&request)?;
+    events.notify();
+    audit.record(&state, &key, api::AuditAction::SetMaintenance(request))?;
+    Ok(StatusCode::OK)
+}
+
+/// Maintenance windows of all groups. Windows of single hosts are part of the host
+pub async fn group_maintenance(
+    State(state): State<Arc<RwLock<AppState>>>,
+    HttpSig(key): HttpSig,
+) -> Result<Json<Vec<api::maintenance::GroupMaintenance>>, StateError> {
+    let state = state.read_arc();
+    state.authorize(&key, api::Permission::Read)?;
+    Ok(Json(state.group_maintenance().to_vec()))
+}
+
+/// Issue a version again which the host reported as failed
+pub async fn retry_deployment(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    State(events): State<Arc<ProvisionEvents>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(request): VerifiedJson<api::RetryRequest>,
+) -> Result<StatusCode, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Operate)?;
+    state.retry_deployment(&request.hostname)?;
+    events.notify();
+    audit.record(&state, &key, api::AuditAction::RetryDeployment(request))?;
     Ok(StatusCode::OK)
 }
@@ -1,41 +1,65 @@
 use std::sync::Arc;
 
-use axum::{extract::State, http::StatusCode};
+use axum::{Json, extract::State, http::StatusCode};
 use ed25519_dalek::VerifyingKey;
 use parking_lot::RwLock;
 
 use crate::{
     AppState,
+    audit::AuditLog,
     httpsig::{HttpSig, VerifiedJson},
     state::StateError,
 };
 
 pub async fn add_key(
     State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
     HttpSig(http_key): HttpSig,
 
-    VerifiedJson(api::AddKey { key, level }): VerifiedJson<api::AddKey>,
+    VerifiedJson(request): VerifiedJson<api::AddKey>,
 ) -> Result<StatusCode, StateError> {
     let mut state = state.write_arc();
 
-    state.auth_admin(&http_key)?;
+    state.authorize(&http_key, api::Permission::Admin)?;
 
-    state.add_key(key, level);
+    let action = api::AuditAction::AddKey {
+        key: request.key,
+        level: request.level,
+        label: request.label.clone(),
+        expires: request.expires.clone(),
+        scope: request.scope.clone(),
+    };
+    state.add_key(request);
+
+    audit.record(&state, &http_key, action)?;
 
     Ok(StatusCode::CREATED)
 }
 
 pub async fn remove_key(
     State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
     HttpSig(http_key): HttpSig,
 
     VerifiedJson(key): VerifiedJson<VerifyingKey>,
 ) -> Result<StatusCode, StateError> {
     let mut state = state.write_arc();
 
-    state.auth_admin(&http_key)?;
+    state.authorize(&http_key, api::Permission::Admin)?;
 
     state.remove_key(&key);
 
+    audit.record(&state, &http_key, api::AuditAction::RemoveKey { key })?;
+
     Ok(StatusCode::OK)
 }
+
+/// All admin and build keys - host keys are listed with the hosts
+pub async fn list_keys(
+    State(state): State<Arc<RwLock<AppState>>>,
+    HttpSig(key): HttpSig,
+) -> Result<Json<Vec<api::KeyInfo>>, StateError> {
+    let state = state.read_arc();
+    state.authorize(&key, api::Permission::Admin)?;
+    Ok(Json(state.credentials().to_vec()))
+}
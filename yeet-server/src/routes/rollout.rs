@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use axum::{Json, extract::State, http::StatusCode};
+use parking_lot::RwLock;
+
+use crate::{
+    audit::AuditLog,
+    events::ProvisionEvents,
+    httpsig::{HttpSig, VerifiedJson},
+    state::{AppState, StateError},
+};
+
+/// All rollouts including finished ones, oldest first
+pub async fn rollouts(
+    State(state): State<Arc<RwLock<AppState>>>,
+    HttpSig(key): HttpSig,
+) -> Result<Json<Vec<api::Rollout>>, StateError> {
+    let state = state.read_arc();
+    state.authorize(&key, api::Permission::Read)?;
+    Ok(Json(state.rollouts().to_vec()))
+}
+
+/// Stop promoting a rollout. Hosts which already got the update keep it
+pub async fn pause(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(api::RolloutRequest { id }): VerifiedJson<api::RolloutRequest>,
+) -> Result<StatusCode, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Operate)?;
+    state.pause_rollout(id)?;
+    audit.record(&state, &key, api::AuditAction::PauseRollout { id })?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn resume(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(api::RolloutRequest { id }): VerifiedJson<api::RolloutRequest>,
+) -> Result<StatusCode, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Operate)?;
+    state.resume_rollout(id)?;
+    audit.record(&state, &key, api::AuditAction::ResumeRollout { id })?;
+    Ok(StatusCode::OK)
+}
+
+/// Revert hosts to their previous version. Returns the reverted hosts
+pub async fn rollback(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    State(events): State<Arc<ProvisionEvents>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(target): VerifiedJson<api::RollbackTarget>,
+) -> Result<Json<Vec<String>>, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Operate)?;
+    let hosts = state.rollback(&target)?;
+    events.notify();
+    audit.record(
+        &state,
+        &key,
+        api::AuditAction::Rollback {
+            target,
+            hosts: hosts.clone(),
+        },
+    )?;
+    Ok(Json(hosts))
+}
@@ -12,7 +12,7 @@ pub async fn status(
     HttpSig(key): HttpSig,
 ) -> Result<Json<Vec<api::Host>>, StateError> {
     let state = state.read_arc();
-    state.auth_admin(&key)?;
+    state.authorize(&key, api::Permission::Read)?;
     Ok(Json(state.hosts().cloned().collect()))
 }
 
@@ -22,6 +22,6 @@ pub async fn hosts_by_key(
     HttpSig(key): HttpSig,
 ) -> Result<Json<HashMap<String, VerifyingKey>>, StateError> {
     let state = state.read_arc();
-    state.auth_admin(&key)?;
+    state.authorize(&key, api::Permission::Read)?;
     Ok(Json(state.hosts_by_key()))
 }
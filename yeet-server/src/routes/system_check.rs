@@ -1,20 +1,72 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use axum::{Json, extract::State};
+use ed25519_dalek::VerifyingKey;
 use parking_lot::RwLock;
+use tokio::time::{Instant, timeout_at};
 
 use crate::{
     AppState,
+    events::ProvisionEvents,
     httpsig::{HttpSig, VerifiedJson},
     state::StateError,
 };
 
+/// How long `/system/check/wait` holds a request. Stays below the common proxy timeout of 60s
+const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(50);
+
 pub async fn system_check(
     State(state): State<Arc<RwLock<AppState>>>,
+    State(events): State<Arc<ProvisionEvents>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(api::VersionRequest { store_path }): VerifiedJson<api::VersionRequest>,
+) -> Result<Json<api::AgentAction>, StateError> {
+    Ok(Json(check(&state, &events, store_path, &key)?))
+}
+
+/// Long-poll variant of [`system_check`]. Holds the request until the host has something to
+/// do or [`LONG_POLL_TIMEOUT`] passed
+pub async fn system_check_wait(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(events): State<Arc<ProvisionEvents>>,
     HttpSig(key): HttpSig,
     VerifiedJson(api::VersionRequest { store_path }): VerifiedJson<api::VersionRequest>,
 ) -> Result<Json<api::AgentAction>, StateError> {
-    let mut state = state.write_arc();
+    // Subscribe before checking so that no change between the check and the wait gets lost
+    let mut changes = events.subscribe();
+    let deadline = Instant::now() + LONG_POLL_TIMEOUT;
+
+    loop {
+        let action = check(&state, &events, store_path.clone(), &key)?;
+
+        // A detached host has nothing to do either - wait in case it gets attached
+        let idle = matches!(
+            action,
+            api::AgentAction::Nothing | api::AgentAction::Detach
+        );
+        if !idle || events.is_shutting_down() {
+            return Ok(Json(action));
+        }
+
+        if !matches!(timeout_at(deadline, changes.changed()).await, Ok(Ok(()))) {
+            return Ok(Json(action));
+        }
+    }
+}
+
+fn check(
+    state: &RwLock<AppState>,
+    events: &ProvisionEvents,
+    store_path: String,
+    key: &VerifyingKey,
+) -> Result<api::AgentAction, StateError> {
+    let mut state = state.write();
+    let action = state.system_check(store_path, key)?;
+
+    // The reported version could be the last one missing for a wave
+    if state.advance_rollouts() {
+        events.notify();
+    }
 
-    Ok(Json(state.system_check(store_path, &key)?))
+    Ok(action)
 }
@@ -1,10 +1,12 @@
 use std::sync::Arc;
 
-use axum::{extract::State, http::StatusCode};
+use axum::{Json, extract::State, http::StatusCode};
 use parking_lot::RwLock;
 
 use crate::{
     AppState,
+    audit::AuditLog,
+    events::ProvisionEvents,
     httpsig::{HttpSig, VerifiedJson},
     state::StateError,
 };
@@ -13,22 +15,42 @@ use crate::{
 /// The whole request needs to be signed by a build machine.
 /// The update consist of a simple `key` -> `version` and a `substitutor` which is where the agent should get its update
 /// This means that for each origin e.g. cachix, you need to call update seperately
+/// Hosts outside the scope of the key are rejected one by one - the others are still updated
 pub async fn update_hosts(
     State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    State(events): State<Arc<ProvisionEvents>>,
     HttpSig(http_key): HttpSig,
 
-    VerifiedJson(api::HostUpdateRequest {
-        hosts,
-        public_key,
-        substitutor,
-        netrc,
-    }): VerifiedJson<api::HostUpdateRequest>,
-) -> Result<StatusCode, StateError> {
+    VerifiedJson(mut request): VerifiedJson<api::HostUpdateRequest>,
+) -> Result<(StatusCode, Json<api::HostUpdateResponse>), StateError> {
     let mut state = state.write_arc();
 
-    state.auth_build(&http_key)?;
+    state.authorize(&http_key, api::Permission::Publish)?;
+    // The scope and the audit log have to see the hosts of the selector as well
+    state.resolve_selector(&mut request)?;
+    let rejected = state.reject_hosts(&http_key, &mut request.hosts);
 
-    state.update_hosts(hosts, public_key, substitutor, netrc);
+    let action = api::AuditAction::Update {
+        hosts: request.hosts.clone(),
+        public_key: request.public_key.clone(),
+        substitutor: request.substitutor.clone(),
+        netrc: request
+            .netrc
+            .as_ref()
+            .map(|_netrc| api::REDACTED.to_owned()),
+        selector: request.selector.clone(),
+        rollout: request.rollout.clone(),
+        commit: request.commit.clone(),
+    };
 
-    Ok(StatusCode::CREATED)
+    state.update_hosts(request)?;
+    events.notify();
+
+    audit.record(&state, &http_key, action)?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(api::HostUpdateResponse { rejected }),
+    ))
 }
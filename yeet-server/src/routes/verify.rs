@@ -14,6 +14,7 @@ use axum::{Json, extract::State, http::StatusCode};
 use parking_lot::RwLock;
 
 use crate::{
+    audit::AuditLog,
     httpsig::{HttpSig, VerifiedJson},
     state::{AppState, StateError},
 };
@@ -34,10 +35,49 @@ pub async fn add_verification_attempt(
 /// Accept an verification attempt
 pub async fn verify_attempt(
     State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
     HttpSig(key): HttpSig,
     VerifiedJson(acceptance): VerifiedJson<api::VerificationAcceptance>,
 ) -> Result<Json<api::VerificationArtifacts>, StateError> {
     let mut state = state.write_arc();
-    state.auth_admin(&key)?;
-    Ok(Json(state.verify_attempt(acceptance)?))
+    state.authorize(&key, api::Permission::Operate)?;
+    let action = api::AuditAction::AcceptVerification {
+        code: acceptance.code,
+        hostname: acceptance.hostname.clone(),
+    };
+    let artifacts = state.verify_attempt(acceptance)?;
+    audit.record(&state, &key, action)?;
+    Ok(Json(artifacts))
+}
+
+/// Verification attempts waiting for approval so `yeet approve` can offer them without the code
+pub async fn pending_verifications(
+    State(state): State<Arc<RwLock<AppState>>>,
+    HttpSig(key): HttpSig,
+) -> Result<Json<Vec<api::PendingVerification>>, StateError> {
+    let state = state.read_arc();
+    state.authorize(&key, api::Permission::Operate)?;
+    Ok(Json(state.list_pending_verifications()))
+}
+
+/// Drop an verification attempt and optionally block its key
+pub async fn reject_verification(
+    State(state): State<Arc<RwLock<AppState>>>,
+    State(audit): State<Arc<AuditLog>>,
+    HttpSig(key): HttpSig,
+    VerifiedJson(rejection): VerifiedJson<api::VerificationRejection>,
+) -> Result<StatusCode, StateError> {
+    let mut state = state.write_arc();
+    state.authorize(&key, api::Permission::Operate)?;
+    let rejected = state.reject_verification(&rejection)?;
+    audit.record(
+        &state,
+        &key,
+        api::AuditAction::RejectVerification {
+            code: rejection.code,
+            key: rejected,
+            blocklist: rejection.blocklist,
+        },
+    )?;
+    Ok(StatusCode::OK)
 }
@@ -1,17 +1,26 @@
-use std::{
-    cmp::Ordering,
-    collections::{HashMap, HashSet, hash_map},
-};
+use std::collections::{BTreeMap, HashMap, hash_map};
 
+use api::{
+    enroll::{Enrollment, EnrollmentToken, TokenRequest},
+    maintenance::{self, GroupMaintenance, MaintenanceTarget},
+    webhook::Event,
+};
 use axum::http::StatusCode;
 use axum_thiserror::ErrorStatus;
 use ed25519_dalek::VerifyingKey;
 use httpsig_hyper::prelude::{AlgorithmName, PublicKey, VerifyingKey as _};
-use jiff::{ToSpan as _, Zoned};
-use rand::Rng;
+use jiff::{SignedDuration, Zoned};
+use rand::{Rng, distr::Alphanumeric};
 use serde::{Deserialize, Serialize};
 use serde_json_any_key::any_key_map;
+use subtle::ConstantTimeEq as _;
 use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    audit::AuditError, config::VerificationLimits, migration::SchemaVersion, rollout,
+    webhook::Outbox,
+};
 
 #[derive(Error, Debug, ErrorStatus)]
 pub enum StateError {
@@ -19,13 +28,13 @@ pub enum StateError {
     #[status(StatusCode::FORBIDDEN)]
     HostNotFound,
 
-    #[error("The request is authenticated but you lack admin credentials")]
+    #[error("The request is authenticated but the key lacks the `{0}` permission")]
     #[status(StatusCode::FORBIDDEN)]
-    AuthMissingAdmin,
+    MissingPermission(api::Permission),
 
-    #[error("The request is authenticated but you lack build credentials")]
+    #[error("The key expired - ask an admin to add it again")]
     #[status(StatusCode::FORBIDDEN)]
-    AuthMissingBuild,
+    KeyExpired,
 
     #[error(
         "There are too many open verification attempts - limit the visibility of the server to the network"
@@ -41,23 +50,68 @@ pub enum StateError {
     #[status(StatusCode::BAD_REQUEST)]
     KeyAlreadyInUse,
 
+    #[error("Updating hosts by selector requires a store path")]
+    #[status(StatusCode::BAD_REQUEST)]
+    MissingStorePath,
+
     #[error("Verification attempt with code {0} not found")]
     #[status(StatusCode::BAD_REQUEST)]
     AttemptNotFound(u32),
 
+    #[error("The key is blocked - ask an admin to remove it")]
+    #[status(StatusCode::FORBIDDEN)]
+    KeyBlocked,
+
+    #[error("The enrollment token is invalid, expired or used up")]
+    #[status(StatusCode::FORBIDDEN)]
+    InvalidEnrollmentToken,
+
+    #[error("Invalid enrollment token: {0}")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidTokenRequest(&'static str),
+
+    #[error("Enrollment token {0} not found")]
+    #[status(StatusCode::BAD_REQUEST)]
+    EnrollmentTokenNotFound(Uuid),
+
+    #[error("Neither the enrollment token nor the agent provide a hostname")]
+    #[status(StatusCode::BAD_REQUEST)]
+    MissingHostname,
+
+    #[error("There is already a host named {0}")]
+    #[status(StatusCode::CONFLICT)]
+    HostnameTaken(Hostname),
+
     #[error("You have no permission to detach your host")]
     #[status(StatusCode::FORBIDDEN)]
     DetachNotAllowed,
+
+    #[error("Invalid rollout: {0}")]
+    #[status(StatusCode::BAD_REQUEST)]
+    InvalidRollout(String),
+
+    #[error("Rollout {0} not found")]
+    #[status(StatusCode::BAD_REQUEST)]
+    RolloutNotFound(Uuid),
+
+    #[error(transparent)]
+    #[status(StatusCode::INTERNAL_SERVER_ERROR)]
+    Audit(#[from] AuditError),
 }
 
 type Result<T> = core::result::Result<T, StateError>;
 
+/// Resolution of the `last_used` time of admin and build keys
+const TOUCH_INTERVAL: SignedDuration = SignedDuration::from_secs(60);
+
 type Hostname = String;
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Default)]
 pub struct AppState {
-    admin_credentials: HashSet<VerifyingKey>,
-    build_machines_credentials: HashSet<VerifyingKey>,
+    // Bumped by migrations - see `crate::migration`
+    schema_version: SchemaVersion,
+    // Admin and build keys
+    credentials: Vec<api::KeyInfo>,
     // hostname -> Hosts
     hosts: HashMap<Hostname, api::Host>,
     //  keyid -> Key for httpsig
@@ -69,24 +123,132 @@ pub struct AppState {
     verification_attempt: HashMap<u32, (api::VerificationAttempt, Zoned)>,
     // Should hosts be allowed to detach by themself in general
     detach_allowed: bool,
+    // Staged rollouts - finished rollouts are kept as history
+    #[serde(default)]
+    rollouts: Vec<api::Rollout>,
+    // Maintenance windows per tag selector - windows on the host itself take precedence
+    #[serde(default)]
+    maintenance_windows: Vec<GroupMaintenance>,
+    // Webhook events waiting to be delivered
+    #[serde(default)]
+    webhooks: Outbox,
+    // Tokens hosts can enroll with instead of a verification code
+    #[serde(default)]
+    enrollment_tokens: Vec<EnrollmentToken>,
+    // Keys of rejected verification attempts which may not try again
+    #[serde(default)]
+    blocked_keys: Vec<VerifyingKey>,
+    // Hosts which were pre-provisioned by an admin but did not enroll yet
+    #[serde(default)]
+    placeholders: HashMap<Hostname, api::PlaceholderHost>,
+    // Comes from the config - see `set_verification_limits`
+    #[serde(skip)]
+    verification_limits: VerificationLimits,
+}
+
+/// Short description of the machine from a `nixos-facter` report. Unknown fields are skipped so
+/// that reports of other facter versions still give a summary
+fn facter_summary(report: &str) -> Option<String> {
+    let report: serde_json::Value = serde_json::from_str(report).ok()?;
+    let smbios = &report["smbios"]["system"];
+    let model = [&smbios["manufacturer"], &smbios["product"]]
+        .iter()
+        .filter_map(|field| field.as_str())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let parts: Vec<&str> = [
+        report["system"].as_str(),
+        report["virtualisation"]
+            .as_str()
+            .filter(|virtualisation| *virtualisation != "none"),
+        Some(model.as_str()).filter(|model| !model.is_empty()),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    (!parts.is_empty()).then(|| parts.join(", "))
 }
 
 impl AppState {
-    #[expect(unused_must_use)]
+    pub fn set_verification_limits(&mut self, limits: VerificationLimits) {
+        self.verification_limits = limits;
+    }
+
+    fn is_attempt_expired(time: &Zoned, limits: VerificationLimits) -> bool {
+        Zoned::now().duration_since(time).abs() > limits.expire_after()
+    }
+
     fn drain_verification_attempts(&mut self) {
-        self.verification_attempt.extract_if(|_key, (_kv, time)| {
-            matches!(
-                (&Zoned::now() - &*time).abs().compare(15.minutes()),
-                Ok(Ordering::Greater)
-            )
-        });
+        let limits = self.verification_limits;
+        self.verification_attempt
+            .retain(|_code, (_attempt, time)| !Self::is_attempt_expired(time, limits));
+    }
+
+    /// Verification attempts which did not expire yet. The pending list, the metrics and the
+    /// `max_attempts` cap all count these
+    fn live_verification_attempts(
+        &self,
+    ) -> impl Iterator<Item = (&u32, &(api::VerificationAttempt, Zoned))> {
+        self.verification_attempt
+            .iter()
+            .filter(|(_code, (_attempt, time))| {
+                !Self::is_attempt_expired(time, self.verification_limits)
+            })
+    }
+
+    /// Verification attempts which did not expire yet
+    pub(crate) fn pending_verifications(&self) -> usize {
+        self.live_verification_attempts().count()
+    }
+
+    /// Verification attempts which did not expire yet, oldest first
+    pub fn list_pending_verifications(&self) -> Vec<api::PendingVerification> {
+        let mut pending: Vec<api::PendingVerification> = self
+            .live_verification_attempts()
+            .map(|(code, (attempt, time))| api::PendingVerification {
+                code: *code,
+                key: attempt.key,
+                store_path: attempt.store_path.clone(),
+                since: time.clone(),
+                hostname: attempt.hostname.clone(),
+                configuration: attempt.configuration.clone(),
+                facter_summary: attempt
+                    .artifacts
+                    .nixos_facter
+                    .as_deref()
+                    .and_then(facter_summary),
+            })
+            .collect();
+        pending.sort_by(|a, b| a.since.cmp(&b.since));
+        pending
+    }
+
+    /// Drop a verification attempt. A blocklisted key cannot try again until it is removed with
+    /// `remove_key`. Returns the key of the attempt
+    pub fn reject_verification(
+        &mut self,
+        rejection: &api::VerificationRejection,
+    ) -> Result<VerifyingKey> {
+        self.drain_verification_attempts();
+        let (attempt, _time) = self
+            .verification_attempt
+            .remove(&rejection.code)
+            .ok_or(StateError::AttemptNotFound(rejection.code))?;
+        if rejection.blocklist && !self.blocked_keys.contains(&attempt.key) {
+            self.blocked_keys.push(attempt.key);
+        }
+        Ok(attempt.key)
     }
 
     /// Agent want to authenticate so he sends a request
     /// This can be approved by an admin with `verify_attempt`
     pub fn add_verification_attempt(&mut self, attempt: api::VerificationAttempt) -> Result<u32> {
         self.drain_verification_attempts();
-        if self.verification_attempt.len() >= 10 {
+        if self.blocked_keys.contains(&attempt.key) {
+            return Err(StateError::KeyBlocked);
+        }
+        if self.pending_verifications() >= self.verification_limits.max_attempts {
             return Err(StateError::TooManyVerificationAttempts);
         }
 
@@ -107,6 +269,9 @@ impl AppState {
         // attempt is safe to add -> create a random number
         let verification = rand::rng().random_range(100_000..=999_999);
 
+        self.webhooks.emit(Event::VerificationPending {
+            store_path: attempt.store_path.clone(),
+        });
         self.verification_attempt
             .insert(verification, (attempt, Zoned::now()));
 
@@ -120,29 +285,230 @@ impl AppState {
         acceptance: api::VerificationAcceptance,
     ) -> Result<api::VerificationArtifacts> {
         self.drain_verification_attempts();
+        self.check_hostname_free(&acceptance.hostname)?;
 
         let (attempt, first_ping) = self
             .verification_attempt
             .remove(&acceptance.code)
             .ok_or(StateError::AttemptNotFound(acceptance.code))?;
 
-        let signing_key = PublicKey::from_bytes(AlgorithmName::Ed25519, attempt.key.as_bytes())
+        self.register_host(
+            attempt.key,
+            acceptance.hostname,
+            attempt.store_path,
+            first_ping,
+            api::Tags::new(),
+        );
+        Ok(attempt.artifacts)
+    }
+
+    /// A new host must not replace an enrolled one. Remove the old host first
+    fn check_hostname_free(&self, hostname: &Hostname) -> Result<()> {
+        if self.hosts.contains_key(hostname) {
+            return Err(StateError::HostnameTaken(hostname.clone()));
+        }
+        Ok(())
+    }
+
+    /// A placeholder with the same name is taken over - the host gets its tags and switches to
+    /// its version right away
+    fn register_host(
+        &mut self,
+        key: VerifyingKey,
+        hostname: Hostname,
+        store_path: api::StorePath,
+        first_ping: Zoned,
+        tags: api::Tags,
+    ) {
+        let signing_key = PublicKey::from_bytes(AlgorithmName::Ed25519, key.as_bytes())
             .expect("Verifying key already is validated");
 
-        self.host_by_key
-            .insert(attempt.key, acceptance.hostname.clone());
-        self.hosts.insert(
-            acceptance.hostname.clone(),
-            api::Host {
-                name: acceptance.hostname,
-                last_ping: first_ping.clone(),
+        let placeholder = self.placeholders.remove(&hostname);
+        let mut host = api::Host {
+            name: hostname.clone(),
+            last_ping: first_ping.clone(),
+            provision_state: api::ProvisionState::NotSet,
+            version_history: vec![api::HostVersion {
+                store_path,
+                since: first_ping,
+                commit: None,
+            }],
+            detach_allowed: None,
+            tags,
+            provision_history: Vec::new(),
+            maintenance_windows: Vec::new(),
+            update_pending_until: None,
+            last_deployment: None,
+            failed_version: None,
+        };
+        if let Some(placeholder) = placeholder {
+            for (key, value) in placeholder.tags {
+                host.tags.entry(key).or_insert(value);
+            }
+            if let api::ProvisionState::Provisioned(version) = placeholder.provision_state {
+                host.push_update(version);
+            }
+        }
+
+        self.host_by_key.insert(key, hostname.clone());
+        self.hosts.insert(hostname, host);
+        self.keyids.insert(signing_key.key_id(), key);
+    }
+
+    /// Pre-provision a host. Tags of an existing placeholder are replaced
+    pub fn create_placeholder(&mut self, request: api::PlaceholderRequest) -> Result<()> {
+        let api::PlaceholderRequest { hostname, tags } = request;
+        self.check_hostname_free(&hostname)?;
+        self.placeholders
+            .entry(hostname.clone())
+            .or_insert_with(|| api::PlaceholderHost {
+                name: hostname,
                 provision_state: api::ProvisionState::NotSet,
-                version_history: vec![(attempt.store_path, first_ping)],
-                detach_allowed: None,
-            },
+                tags: api::Tags::new(),
+                created: Zoned::now(),
+            })
+            .tags = tags;
+        Ok(())
+    }
+
+    pub fn placeholders(&self) -> Vec<api::PlaceholderHost> {
+        let mut placeholders: Vec<_> = self.placeholders.values().cloned().collect();
+        placeholders.sort_by(|a, b| a.name.cmp(&b.name));
+        placeholders
+    }
+
+    pub fn remove_placeholder(&mut self, hostname: &Hostname) -> Result<()> {
+        self.placeholders
+            .remove(hostname)
+            .map(|_placeholder| ())
+            .ok_or(StateError::HostNotFound)
+    }
+
+    /// Tags of a host or placeholder
+    fn tags(&self, hostname: &str) -> Option<&api::Tags> {
+        self.hosts.get(hostname).map(|host| &host.tags).or_else(|| {
+            self.placeholders
+                .get(hostname)
+                .map(|placeholder| &placeholder.tags)
+        })
+    }
+
+    /// Mint a token hosts can enroll with. The returned token is the only copy with the secret
+    /// which leaves the server
+    pub fn create_enrollment_token(&mut self, request: TokenRequest) -> Result<EnrollmentToken> {
+        let TokenRequest {
+            hostname,
+            tags,
+            uses,
+            expires,
+        } = request;
+        let now = Zoned::now();
+
+        if uses == 0 {
+            return Err(StateError::InvalidTokenRequest("uses has to be at least 1"));
+        }
+        if hostname.is_some() && uses > 1 {
+            return Err(StateError::InvalidTokenRequest(
+                "a token with a hostname can only be used once",
+            ));
+        }
+        if expires <= now {
+            return Err(StateError::InvalidTokenRequest("expires is in the past"));
+        }
+
+        let token = EnrollmentToken {
+            id: Uuid::now_v7(),
+            token: rand::rng()
+                .sample_iter(&Alphanumeric)
+                .take(32)
+                .map(char::from)
+                .collect(),
+            hostname,
+            tags,
+            uses_left: uses,
+            created: now,
+            expires,
+        };
+        self.enrollment_tokens.push(token.clone());
+        Ok(token)
+    }
+
+    /// Tokens which are not expired yet with their secret redacted
+    pub fn enrollment_tokens(&self) -> Vec<EnrollmentToken> {
+        let now = Zoned::now();
+        self.enrollment_tokens
+            .iter()
+            .filter(|token| !token.is_expired(&now))
+            .map(|token| EnrollmentToken {
+                token: api::REDACTED.to_owned(),
+                ..token.clone()
+            })
+            .collect()
+    }
+
+    pub fn revoke_enrollment_token(&mut self, id: Uuid) -> Result<()> {
+        let before = self.enrollment_tokens.len();
+        self.enrollment_tokens.retain(|token| token.id != id);
+        if self.enrollment_tokens.len() == before {
+            return Err(StateError::EnrollmentTokenNotFound(id));
+        }
+        Ok(())
+    }
+
+    /// Register a host with an enrollment token instead of a verification code.
+    /// Returns the hostname and the id of the used token
+    pub fn enroll(&mut self, enrollment: Enrollment) -> Result<(Hostname, Uuid)> {
+        let now = Zoned::now();
+        self.enrollment_tokens
+            .retain(|token| !token.is_expired(&now) && token.uses_left > 0);
+
+        let index = self
+            .enrollment_tokens
+            .iter()
+            // Constant time so that the response time does not leak how much of a token matched
+            .position(|token| bool::from(token.token.as_bytes().ct_eq(enrollment.token.as_bytes())))
+            .ok_or(StateError::InvalidEnrollmentToken)?;
+        let token = self
+            .enrollment_tokens
+            .get(index)
+            .ok_or(StateError::InvalidEnrollmentToken)?;
+
+        if self.keyids.values().any(|key| key == &enrollment.key) {
+            return Err(StateError::KeyAlreadyInUse);
+        }
+        if self.blocked_keys.contains(&enrollment.key) {
+            return Err(StateError::KeyBlocked);
+        }
+        // Only a token issued for the hostname may take over its placeholder
+        let hostname = match (&token.hostname, enrollment.hostname) {
+            (Some(hostname), _) => hostname.clone(),
+            (None, Some(hostname)) if self.placeholders.contains_key(&hostname) => {
+                return Err(StateError::HostnameTaken(hostname));
+            }
+            (None, Some(hostname)) => hostname,
+            (None, None) => return Err(StateError::MissingHostname),
+        };
+        self.check_hostname_free(&hostname)?;
+
+        let id = token.id;
+        let tags = token.tags.clone();
+        if token.uses_left <= 1 {
+            self.enrollment_tokens.remove(index);
+        } else if let Some(token) = self.enrollment_tokens.get_mut(index) {
+            token.uses_left -= 1;
+        }
+
+        // The key does not need to be approved anymore
+        self.verification_attempt
+            .retain(|_code, (attempt, _time)| attempt.key != enrollment.key);
+        self.register_host(
+            enrollment.key,
+            hostname.clone(),
+            enrollment.store_path,
+            now,
+            tags,
         );
-        self.keyids.insert(signing_key.key_id(), attempt.key);
-        Ok(attempt.artifacts)
+        Ok((hostname, id))
     }
 
     /// This is the "ping" command every client should send in a specific interval.
@@ -171,6 +537,15 @@ impl AppState {
     /// # Lastly if all 3 are the same do nothing
     /// -> Nothing
     ///
+    /// # A version which the agent reported as failed is not issued again
+    /// if `host.failed_version` == `host.provision_state`
+    ///     -> Nothing
+    ///
+    /// # Outside of the maintenance windows of the host the update is held back
+    /// if not `maintenance::is_open`
+    ///     `host.update_pending_until` = next window
+    ///     -> Nothing
+    ///
     /// ====== if `host.provision_state` == Detached
     ///
     /// # check if `store_path` is the same as `host.latest_store_path()` if not the update `host.latest_store_path()`
@@ -188,6 +563,13 @@ impl AppState {
             .hosts
             .get_mut(hostname)
             .ok_or(StateError::HostNotFound)?;
+        let windows = maintenance::windows_for(
+            &host.maintenance_windows,
+            &host.tags,
+            &self.maintenance_windows,
+        );
+        let now = Zoned::now();
+        host.update_pending_until = None;
 
         let action = match host.provision_state.clone() {
             api::ProvisionState::NotSet => api::AgentAction::Nothing,
@@ -203,10 +585,22 @@ impl AppState {
                 // Host has completed an update -> update last seen store path
                 if &store_path != host.latest_store_path() {
                     host.update_store_path(store_path.clone());
+                    if store_path == version.store_path {
+                        self.webhooks.emit(Event::Switched {
+                            hostname: host.name.clone(),
+                            store_path: store_path.clone(),
+                        });
+                    }
                 }
                 // Host is on the newest version
                 if store_path == version.store_path {
                     api::AgentAction::Nothing
+                } else if host.failed_version.as_ref() == Some(&version.store_path) {
+                    // Retrying a failed version needs an admin - see `retry_deployment`
+                    api::AgentAction::Nothing
+                } else if !maintenance::is_open(&windows, &now) {
+                    host.update_pending_until = maintenance::next_open(&windows, &now);
+                    api::AgentAction::Nothing
                 } else {
                     // Host needs to update
                     // TODO: we do not see if we updated fast in succession we only see the latest
@@ -220,53 +614,351 @@ impl AppState {
         Ok(action)
     }
 
+    /// Record the result of a deployment reported by the agent.
+    /// A failed provisioned version is held back until an admin retries it
+    pub fn report_deployment(
+        &mut self,
+        key: &VerifyingKey,
+        report: api::DeploymentReport,
+    ) -> Result<()> {
+        let hostname = self.host_by_key.get(key).ok_or(StateError::HostNotFound)?;
+        let host = self
+            .hosts
+            .get_mut(hostname)
+            .ok_or(StateError::HostNotFound)?;
+
+        if let api::ProvisionState::Provisioned(version) = &host.provision_state
+            && version.store_path == report.store_path
+            && report.outcome != api::DeploymentOutcome::Switched
+        {
+            host.failed_version = Some(report.store_path.clone());
+        }
+        if report.outcome != api::DeploymentOutcome::Switched {
+            self.webhooks.emit(Event::SwitchFailed {
+                hostname: host.name.clone(),
+                report: report.clone(),
+            });
+        }
+        host.last_deployment = Some((report, Zoned::now()));
+        Ok(())
+    }
+
+    /// Issue the failed version of a host again
+    pub fn retry_deployment(&mut self, hostname: &Hostname) -> Result<()> {
+        let host = self
+            .hosts
+            .get_mut(hostname)
+            .ok_or(StateError::HostNotFound)?;
+        host.failed_version = None;
+        Ok(())
+    }
+
     /// Endpoint to set a new version for a host.
     /// The whole request needs to be signed by a build machine.
     /// The update consist of a simple `name` -> `version` and a `substitutor` which is where the agent should get its update
     /// This means that for each origin e.g. cachix, you need to call update seperately
-    pub fn update_hosts(
-        &mut self,
-        mut hosts: HashMap<String, api::StorePath>,
-        public_key: String,
-        substitutor: String,
-        netrc: Option<String>,
-    ) {
-        let _unknown_hosts = hosts
-            .extract_if(|name, _store| !self.hosts.contains_key(name))
-            .collect::<HashMap<String, api::StorePath>>();
+    /// The selector has to be resolved with `resolve_selector` beforehand - only the named
+    /// hosts are updated.
+    /// With a rollout plan only the first wave gets the update now - see `crate::rollout`
+    pub fn update_hosts(&mut self, request: api::HostUpdateRequest) -> Result<()> {
+        let api::HostUpdateRequest {
+            hosts,
+            public_key,
+            substitutor,
+            netrc,
+            selector: _,
+            store_path: _,
+            rollout,
+            commit,
+        } = request;
 
-        for (name, store_path) in hosts {
-            let host = self
-                .hosts
-                .get_mut(&name)
-                .expect("Race condition because we checked above - maybe change this TOCTOU");
-            let version = api::RemoteStorePath {
-                store_path: store_path.clone(),
-                substitutor: substitutor.clone(),
-                public_key: public_key.clone(),
-                netrc: netrc.clone(),
+        if let Some(plan) = &rollout {
+            rollout::validate(plan).map_err(StateError::InvalidRollout)?;
+        }
+
+        let mut versions: HashMap<Hostname, api::RemoteStorePath> = hosts
+            .into_iter()
+            .map(|(name, store_path)| {
+                let version = api::RemoteStorePath {
+                    store_path,
+                    substitutor: substitutor.clone(),
+                    public_key: public_key.clone(),
+                    netrc: netrc.clone(),
+                    commit: commit.clone(),
+                };
+                (name, version)
+            })
+            .collect();
+
+        // `reject_hosts` already reported them
+        versions.retain(|name, _version| self.tags(name).is_some());
+
+        // The newest update of a host wins - older rollouts must not overwrite it in a later wave
+        self.forget_in_rollouts(|hostname| versions.contains_key(hostname));
+
+        let rollout_id = rollout.as_ref().map(|_plan| Uuid::now_v7());
+        if !versions.is_empty() {
+            self.webhooks.emit(Event::Published {
+                hosts: versions
+                    .iter()
+                    .map(|(name, version)| (name.clone(), version.store_path.clone()))
+                    .collect(),
+                commit,
+                rollout: rollout_id,
+            });
+        }
+
+        // Placeholders do not run anything which could break so they are not part of rollouts
+        let now = Zoned::now();
+        let placeholders = versions.extract_if(|name, _version| !self.hosts.contains_key(name));
+        for (name, version) in placeholders {
+            if let Some(placeholder) = self.placeholders.get_mut(&name) {
+                placeholder.provision_state = api::ProvisionState::Provisioned(version);
+            }
+        }
+
+        let (Some(plan), Some(id)) = (rollout, rollout_id) else {
+            for (name, version) in versions {
+                let host = self
+                    .hosts
+                    .get_mut(&name)
+                    .expect("Race condition because we checked above - maybe change this TOCTOU");
+                host.push_update(version);
+            }
+            return Ok(());
+        };
+
+        let rollout = api::Rollout {
+            id,
+            plan,
+            hosts: versions,
+            wave: 0,
+            wave_started: now.clone(),
+            created: now,
+            state: api::RolloutState::Running,
+        };
+        self.push_wave(&rollout);
+        self.rollouts.push(rollout);
+
+        Ok(())
+    }
+
+    /// Push the update to all hosts of the current wave of the rollout
+    fn push_wave(&mut self, rollout: &api::Rollout) {
+        for (name, version) in rollout::current_wave(rollout) {
+            if let Some(host) = self.hosts.get_mut(name) {
+                host.push_update(version.clone());
+            }
+        }
+    }
+
+    /// Start the next wave of every running rollout whose current wave has soaked long enough.
+    /// Returns true if any host got a new version
+    pub fn advance_rollouts(&mut self) -> bool {
+        let now = Zoned::now();
+        let mut promoted = Vec::new();
+
+        for rollout in &mut self.rollouts {
+            if rollout.state != api::RolloutState::Running
+                || !rollout::is_soaked(rollout, &self.hosts, &now)
+            {
+                continue;
+            }
+
+            if rollout.wave + 1 == rollout.plan.waves.len() {
+                rollout.state = api::RolloutState::Completed;
+            } else {
+                rollout.wave += 1;
+                rollout.wave_started = now.clone();
+                promoted.push(rollout.clone());
+            }
+        }
+
+        for rollout in &promoted {
+            self.push_wave(rollout);
+        }
+        !promoted.is_empty()
+    }
+
+    pub(crate) fn rollouts(&self) -> &[api::Rollout] {
+        &self.rollouts
+    }
+
+    /// Remove hosts from active rollouts so that later waves leave them alone
+    fn forget_in_rollouts(&mut self, forget: impl Fn(&Hostname) -> bool) {
+        for active in self
+            .rollouts
+            .iter_mut()
+            .filter(|rollout| rollout.state.is_active())
+        {
+            active.hosts.retain(|hostname, _version| !forget(hostname));
+        }
+    }
+
+    fn rollout_mut(&mut self, id: Uuid) -> Result<&mut api::Rollout> {
+        self.rollouts
+            .iter_mut()
+            .find(|rollout| rollout.id == id)
+            .ok_or(StateError::RolloutNotFound(id))
+    }
+
+    pub fn pause_rollout(&mut self, id: Uuid) -> Result<()> {
+        let rollout = self.rollout_mut(id)?;
+        if rollout.state != api::RolloutState::Running {
+            return Err(StateError::InvalidRollout(format!(
+                "Only running rollouts can be paused - rollout is {:?}",
+                rollout.state
+            )));
+        }
+        rollout.state = api::RolloutState::Paused;
+        Ok(())
+    }
+
+    pub fn resume_rollout(&mut self, id: Uuid) -> Result<()> {
+        let rollout = self.rollout_mut(id)?;
+        if rollout.state != api::RolloutState::Paused {
+            return Err(StateError::InvalidRollout(format!(
+                "Only paused rollouts can be resumed - rollout is {:?}",
+                rollout.state
+            )));
+        }
+        rollout.state = api::RolloutState::Running;
+        Ok(())
+    }
+
+    /// Revert hosts to the version they were provisioned with before.
+    /// For a rollout only hosts which still have the version of the rollout are reverted.
+    /// Returns the hosts which were reverted
+    pub fn rollback(&mut self, target: &api::RollbackTarget) -> Result<Vec<Hostname>> {
+        let hosts: Vec<(Hostname, Option<api::RemoteStorePath>)> = match target {
+            api::RollbackTarget::Rollout(id) => {
+                let rollout = self.rollout_mut(*id)?;
+                if rollout.state == api::RolloutState::RolledBack {
+                    return Err(StateError::InvalidRollout(
+                        "Rollout is already rolled back".to_owned(),
+                    ));
+                }
+                rollout.state = api::RolloutState::RolledBack;
+
+                let rollout = &*rollout;
+                rollout
+                    .hosts
+                    .iter()
+                    .filter(|(hostname, _version)| rollout::is_included(rollout, hostname))
+                    .map(|(hostname, version)| (hostname.clone(), Some(version.clone())))
+                    .collect()
+            }
+            api::RollbackTarget::Hosts(hostnames) => {
+                if hostnames
+                    .iter()
+                    .any(|hostname| !self.hosts.contains_key(hostname))
+                {
+                    return Err(StateError::HostNotFound);
+                }
+                self.forget_in_rollouts(|hostname| hostnames.contains(hostname));
+                hostnames
+                    .iter()
+                    .map(|hostname| (hostname.clone(), None))
+                    .collect()
+            }
+        };
+
+        let mut reverted = Vec::new();
+        for (hostname, expected) in hosts {
+            let Some(host) = self.hosts.get_mut(&hostname) else {
+                continue;
             };
+            // Host got a newer update since - do not touch it
+            if let Some(expected) = expected
+                && host.provision_state != api::ProvisionState::Provisioned(expected)
+            {
+                continue;
+            }
+            if host.rollback().is_some() {
+                reverted.push(hostname);
+            }
+        }
+        reverted.sort();
+        Ok(reverted)
+    }
+
+    fn credential(&self, key: &VerifyingKey) -> Option<&api::KeyInfo> {
+        self.credentials
+            .iter()
+            .find(|credential| credential.key == *key)
+    }
 
-            host.push_update(version);
+    /// Check that the key has a role which grants the permission. Host keys have no role
+    pub fn authorize(&self, key: &VerifyingKey, permission: api::Permission) -> Result<()> {
+        match self.credential(key) {
+            Some(credential) if credential.is_expired(&Zoned::now()) => Err(StateError::KeyExpired),
+            Some(credential) if credential.level.allows(permission) => Ok(()),
+            _ => Err(StateError::MissingPermission(permission)),
         }
     }
 
-    pub fn auth_build(&self, key: &VerifyingKey) -> Result<()> {
-        if self.admin_credentials.contains(key) || self.build_machines_credentials.contains(key) {
-            Ok(())
-        } else {
-            Err(StateError::AuthMissingBuild)
+    /// Whether the key may publish to the host. Unknown hosts are matched by their name only
+    pub fn in_scope(&self, key: &VerifyingKey, hostname: &str) -> bool {
+        let Some(scope) = self
+            .credential(key)
+            .and_then(|credential| credential.scope.as_ref())
+        else {
+            return true;
+        };
+        match self.tags(hostname) {
+            Some(tags) => scope.allows(hostname, tags),
+            None => scope.allows(hostname, &api::Tags::default()),
         }
     }
 
-    pub fn auth_admin(&self, key: &VerifyingKey) -> Result<()> {
-        if self.admin_credentials.contains(key) {
-            Ok(())
-        } else {
-            Err(StateError::AuthMissingAdmin)
+    /// Take the hosts out of the update which do not exist or which the key may not publish to.
+    /// The rest of the update is still applied
+    pub fn reject_hosts(
+        &self,
+        key: &VerifyingKey,
+        hosts: &mut HashMap<Hostname, api::StorePath>,
+    ) -> BTreeMap<Hostname, api::UpdateRejection> {
+        hosts
+            .extract_if(|hostname, _store_path| {
+                self.tags(hostname).is_none() || !self.in_scope(key, hostname)
+            })
+            .map(|(hostname, _store_path)| {
+                let rejection = if self.tags(&hostname).is_none() {
+                    api::UpdateRejection::UnknownHost
+                } else {
+                    api::UpdateRejection::OutOfScope
+                };
+                (hostname, rejection)
+            })
+            .collect()
+    }
+
+    /// Whether `touch_key` would record a new use. Only admin and build keys are tracked and at
+    /// most every [`TOUCH_INTERVAL`] so that signed requests rarely need the write lock
+    pub fn needs_touch(&self, key: &VerifyingKey, now: &Zoned) -> bool {
+        self.credential(key).is_some_and(|credential| {
+            credential
+                .last_used
+                .as_ref()
+                .is_none_or(|last_used| now.duration_since(last_used) >= TOUCH_INTERVAL)
+        })
+    }
+
+    /// Remember when an admin or build key was last used
+    pub fn touch_key(&mut self, key: &VerifyingKey) {
+        if let Some(credential) = self
+            .credentials
+            .iter_mut()
+            .find(|credential| credential.key == *key)
+        {
+            credential.last_used = Some(Zoned::now());
         }
     }
 
+    pub(crate) fn credentials(&self) -> &[api::KeyInfo] {
+        &self.credentials
+    }
+
     pub(crate) fn hosts(&self) -> hash_map::Values<'_, String, api::Host> {
         self.hosts.values()
     }
@@ -279,22 +971,36 @@ impl AppState {
             .collect()
     }
 
-    pub fn add_key(&mut self, key: VerifyingKey, level: api::AuthLevel) {
+    /// Adding a known key again replaces its level, label and expiry
+    pub fn add_key(&mut self, request: api::AddKey) {
+        let api::AddKey {
+            key,
+            level,
+            label,
+            expires,
+            scope,
+        } = request;
         let signing_key = PublicKey::from_bytes(AlgorithmName::Ed25519, key.as_bytes())
             .expect("Could not convert ED25519 key to httpsig key - wtf");
-        if level == api::AuthLevel::Admin {
-            self.admin_credentials.insert(key);
-        } else {
-            self.build_machines_credentials.insert(key);
-        }
+
+        self.credentials.retain(|credential| credential.key != key);
+        self.credentials.push(api::KeyInfo {
+            key,
+            level,
+            label,
+            created: Zoned::now(),
+            last_used: None,
+            expires,
+            scope,
+        });
         self.keyids.insert(signing_key.key_id(), key);
     }
 
     pub fn remove_key(&mut self, key: &VerifyingKey) {
         let signing_key = PublicKey::from_bytes(AlgorithmName::Ed25519, key.as_bytes())
             .expect("Could not convert ED25519 key to httpsig key - wtf");
-        self.admin_credentials.remove(key);
-        self.build_machines_credentials.remove(key);
+        self.credentials.retain(|credential| credential.key != *key);
+        self.blocked_keys.retain(|blocked| blocked != key);
         self.host_by_key.remove(key);
         self.keyids.remove(&signing_key.key_id());
     }
@@ -308,7 +1014,7 @@ impl AppState {
         let keys = self.host_by_key.extract_if(|_key, name| name == hostname);
 
         for (key, _hostname) in keys {
-            let _ = self.keyids.extract_if(|_id, k| k == &key);
+            self.keyids.retain(|_id, k| k != &key);
         }
 
         Ok(host)
@@ -333,6 +1039,95 @@ impl AppState {
         Ok(())
     }
 
+    /// Add the hosts matching the selector of the update to its named hosts. Named hosts keep
+    /// their own version
+    pub fn resolve_selector(&self, request: &mut api::HostUpdateRequest) -> Result<()> {
+        let Some(selector) = &request.selector else {
+            return Ok(());
+        };
+        let store_path = request
+            .store_path
+            .as_ref()
+            .ok_or(StateError::MissingStorePath)?;
+        for hostname in self.select_hosts(selector) {
+            request
+                .hosts
+                .entry(hostname)
+                .or_insert_with(|| store_path.clone());
+        }
+        Ok(())
+    }
+
+    /// Names of all hosts matching the selector
+    pub fn select_hosts(&self, selector: &api::TagSelector) -> Vec<Hostname> {
+        let mut hostnames: Vec<Hostname> = self
+            .hosts
+            .values()
+            .filter(|host| selector.matches(&host.tags))
+            .map(|host| host.name.clone())
+            .chain(
+                self.placeholders
+                    .values()
+                    .filter(|placeholder| selector.matches(&placeholder.tags))
+                    .map(|placeholder| placeholder.name.clone()),
+            )
+            .collect();
+        hostnames.sort();
+        hostnames
+    }
+
+    pub fn set_tags(
+        &mut self,
+        hostname: &Hostname,
+        set: api::Tags,
+        remove: &[String],
+    ) -> Result<()> {
+        let tags = match self.hosts.get_mut(hostname) {
+            Some(host) => &mut host.tags,
+            None => {
+                &mut self
+                    .placeholders
+                    .get_mut(hostname)
+                    .ok_or(StateError::HostNotFound)?
+                    .tags
+            }
+        };
+
+        for key in remove {
+            tags.remove(key);
+        }
+        tags.extend(set);
+        Ok(())
+    }
+
+    /// Replace the maintenance windows of a host or group. No windows remove them
+    pub fn set_maintenance(&mut self, request: &maintenance::MaintenanceRequest) -> Result<()> {
+        match &request.target {
+            MaintenanceTarget::Host(hostname) => {
+                let host = self
+                    .hosts
+                    .get_mut(hostname)
+                    .ok_or(StateError::HostNotFound)?;
+                host.maintenance_windows.clone_from(&request.windows);
+            }
+            MaintenanceTarget::Group(selector) => {
+                self.maintenance_windows
+                    .retain(|group| group.selector != *selector);
+                if !request.windows.is_empty() {
+                    self.maintenance_windows.push(GroupMaintenance {
+                        selector: selector.clone(),
+                        windows: request.windows.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn group_maintenance(&self) -> &[GroupMaintenance] {
+        &self.maintenance_windows
+    }
+
     pub fn set_global_detach_permission(&mut self, allowed: bool) {
         self.detach_allowed = allowed;
     }
@@ -375,17 +1170,27 @@ impl AppState {
             }
         };
 
+        if host.is_provisioned() {
+            self.webhooks.emit(Event::Detached {
+                hostname: host.name.clone(),
+            });
+        }
         host.detach();
         Ok(())
     }
 
-    // Warning: This should only ever be called by admins because it will bypass detach permissions
+    // Warning: This should only ever be called by operators because it will bypass detach permissions
     pub fn detach_host(&mut self, hostname: &Hostname) -> Result<()> {
         let host = self
             .hosts
             .get_mut(hostname)
             .ok_or(StateError::HostNotFound)?;
 
+        if host.is_provisioned() {
+            self.webhooks.emit(Event::Detached {
+                hostname: host.name.clone(),
+            });
+        }
         host.detach();
         Ok(())
     }
@@ -398,6 +1203,11 @@ impl AppState {
                 .ok_or(StateError::HostNotFound)?
         };
 
+        if host.is_detached() {
+            self.webhooks.emit(Event::Attached {
+                hostname: host.name.clone(),
+            });
+        }
         host.attach();
         Ok(())
     }
@@ -408,15 +1218,312 @@ impl AppState {
             .get_mut(hostname)
             .ok_or(StateError::HostNotFound)?;
 
+        if host.is_detached() {
+            self.webhooks.emit(Event::Attached {
+                hostname: host.name.clone(),
+            });
+        }
         host.attach();
         Ok(())
     }
 
+    pub(crate) fn outbox(&mut self) -> &mut Outbox {
+        &mut self.webhooks
+    }
+
+    /// Queue a webhook event for every host which went stale since the last check
+    pub(crate) fn check_stale_hosts(&mut self, stale_after: SignedDuration, now: &Zoned) {
+        self.webhooks
+            .check_stale(self.hosts.values(), stale_after, now);
+    }
+
+    /// Expired admin keys do not count - they cannot be used to add new keys
     pub fn has_admin_credential(&self) -> bool {
-        !self.admin_credentials.is_empty()
+        let now = Zoned::now();
+        self.credentials.iter().any(|credential| {
+            credential.level == api::AuthLevel::Admin && !credential.is_expired(&now)
+        })
+    }
+
+    /// Human readable name of a key - the hostname for host keys and the label for admin and
+    /// build keys
+    pub fn key_name(&self, key: &VerifyingKey) -> Option<String> {
+        self.host_by_key.get(key).cloned().or_else(|| {
+            self.credential(key)
+                .map(|credential| credential.label.clone())
+                .filter(|label| !label.is_empty())
+        })
     }
 
     pub fn get_key_by_id<S: AsRef<str>>(&self, keyid: S) -> Option<VerifyingKey> {
         self.keyids.get(keyid.as_ref()).copied()
     }
 }
+
+#[cfg(test)]
+mod test_state {
+    use std::collections::HashMap;
+
+    use ed25519_dalek::{SigningKey, VerifyingKey};
+    use jiff::ToSpan as _;
+
+    use crate::state::{AppState, StateError};
+
+    fn key(seed: u8) -> VerifyingKey {
+        SigningKey::from_bytes(&[seed; 32]).verifying_key()
+    }
+
+    fn attempt(seed: u8) -> api::VerificationAttempt {
+        api::VerificationAttempt {
+            key: key(seed),
+            store_path: "/nix/store/abc-nixos-system".to_owned(),
+            artifacts: api::VerificationArtifacts { nixos_facter: None },
+            hostname: None,
+            configuration: None,
+        }
+    }
+
+    fn add_host(state: &mut AppState, seed: u8, hostname: &str, tags: &[(&str, &str)]) {
+        let code = state.add_verification_attempt(attempt(seed)).unwrap();
+        state
+            .verify_attempt(api::VerificationAcceptance {
+                code,
+                hostname: hostname.to_owned(),
+            })
+            .unwrap();
+        let tags = tags
+            .iter()
+            .map(|(key, value)| ((*key).to_owned(), (*value).to_owned()))
+            .collect();
+        state.set_tags(&hostname.to_owned(), tags, &[]).unwrap();
+    }
+
+    fn update(hosts: &[(&str, &str)]) -> api::HostUpdateRequest {
+        api::HostUpdateRequest {
+            hosts: hosts
+                .iter()
+                .map(|(host, store_path)| ((*host).to_owned(), (*store_path).to_owned()))
+                .collect::<HashMap<_, _>>(),
+            public_key: "cache:key".to_owned(),
+            substitutor: "https://cache.example.com".to_owned(),
+            netrc: None,
+            selector: None,
+            store_path: None,
+            rollout: None,
+            commit: None,
+        }
+    }
+
+    fn provisioned<'a>(state: &'a AppState, hostname: &str) -> Option<&'a str> {
+        state.hosts[hostname]
+            .provision_state
+            .store_path()
+            .map(String::as_str)
+    }
+
+    #[test]
+    fn test_expired_attempts_free_slots() {
+        let mut state = AppState::default();
+        let max_attempts = state.verification_limits.max_attempts;
+        for seed in 0..max_attempts {
+            state
+                .add_verification_attempt(attempt(u8::try_from(seed).unwrap()))
+                .unwrap();
+        }
+        assert!(matches!(
+            state.add_verification_attempt(attempt(100)),
+            Err(StateError::TooManyVerificationAttempts)
+        ));
+
+        for (_attempt, time) in state.verification_attempt.values_mut() {
+            *time = time.checked_sub(1.hour()).unwrap();
+        }
+        state.add_verification_attempt(attempt(100)).unwrap();
+        assert_eq!(state.verification_attempt.len(), 1);
+    }
+
+    #[test]
+    fn test_pending_matches_cap() {
+        let mut state = AppState::default();
+        let max_attempts = state.verification_limits.max_attempts;
+        for seed in 0..max_attempts {
+            state
+                .add_verification_attempt(attempt(u8::try_from(seed).unwrap()))
+                .unwrap();
+        }
+        assert_eq!(state.list_pending_verifications().len(), max_attempts);
+
+        // Expired but not drained yet - neither listed nor counted against the cap
+        let (_attempt, time) = state.verification_attempt.values_mut().next().unwrap();
+        *time = time.checked_sub(1.hour()).unwrap();
+        assert_eq!(state.list_pending_verifications().len(), max_attempts - 1);
+        assert_eq!(state.pending_verifications(), max_attempts - 1);
+        state.add_verification_attempt(attempt(100)).unwrap();
+        assert_eq!(state.list_pending_verifications().len(), max_attempts);
+    }
+
+    #[test]
+    fn test_enroll_placeholder() {
+        let mut state = AppState::default();
+        state
+            .create_placeholder(api::PlaceholderRequest {
+                hostname: "web-3".to_owned(),
+                tags: api::Tags::default(),
+            })
+            .unwrap();
+        let token = |state: &mut AppState, hostname: Option<&str>| {
+            state
+                .create_enrollment_token(api::enroll::TokenRequest {
+                    hostname: hostname.map(str::to_owned),
+                    tags: api::Tags::default(),
+                    uses: 1,
+                    expires: jiff::Zoned::now().checked_add(1.hour()).unwrap(),
+                })
+                .unwrap()
+                .token
+        };
+        let enrollment = |token: String, seed: u8| api::enroll::Enrollment {
+            token,
+            key: key(seed),
+            store_path: "/nix/store/abc-nixos-system".to_owned(),
+            hostname: Some("web-3".to_owned()),
+            artifacts: api::VerificationArtifacts { nixos_facter: None },
+        };
+
+        let generic = token(&mut state, None);
+        assert!(matches!(
+            state.enroll(enrollment(generic, 1)),
+            Err(StateError::HostnameTaken(_))
+        ));
+        let named = token(&mut state, Some("web-3"));
+        assert!(matches!(
+            state.enroll(enrollment("wrong".to_owned(), 2)),
+            Err(StateError::InvalidEnrollmentToken)
+        ));
+        state.enroll(enrollment(named, 2)).unwrap();
+        assert!(state.placeholders().is_empty());
+    }
+
+    #[test]
+    fn test_remove_host_forgets_keys() {
+        let mut state = AppState::default();
+        add_host(&mut state, 1, "web-1", &[]);
+        state.remove_host(&"web-1".to_owned()).unwrap();
+        assert!(state.keyids.is_empty());
+        assert!(state.host_by_key.is_empty());
+    }
+
+    #[test]
+    fn test_scope_rejects_hosts() {
+        let mut state = AppState::default();
+        add_host(&mut state, 1, "web-1", &[]);
+        add_host(&mut state, 2, "db", &[("role", "db")]);
+        state.add_key(api::AddKey {
+            key: key(10),
+            level: api::AuthLevel::Build,
+            label: "ci-web".to_owned(),
+            expires: None,
+            scope: Some(api::HostScope {
+                hosts: vec!["web-*".to_owned()],
+                tags: Vec::new(),
+            }),
+        });
+
+        let mut request = update(&[("web-1", "/nix/store/web"), ("db", "/nix/store/db")]);
+        let rejected = state.reject_hosts(&key(10), &mut request.hosts);
+        assert_eq!(
+            rejected.into_iter().collect::<Vec<_>>(),
+            [("db".to_owned(), api::UpdateRejection::OutOfScope)]
+        );
+        state.update_hosts(request).unwrap();
+        assert_eq!(provisioned(&state, "web-1"), Some("/nix/store/web"));
+        assert_eq!(provisioned(&state, "db"), None);
+
+        // Hosts of the selector are out of scope as well
+        let mut request = update(&[]);
+        request.selector = Some("role=db".parse().unwrap());
+        request.store_path = Some("/nix/store/db".to_owned());
+        state.resolve_selector(&mut request).unwrap();
+        let rejected = state.reject_hosts(&key(10), &mut request.hosts);
+        assert_eq!(
+            rejected.into_iter().collect::<Vec<_>>(),
+            [("db".to_owned(), api::UpdateRejection::OutOfScope)]
+        );
+        state.update_hosts(request).unwrap();
+        assert_eq!(provisioned(&state, "db"), None);
+    }
+
+    #[test]
+    fn test_selector_update() {
+        let mut state = AppState::default();
+        add_host(&mut state, 1, "web-1", &[("role", "web")]);
+        add_host(&mut state, 2, "web-2", &[("role", "web")]);
+        add_host(&mut state, 3, "db", &[("role", "db")]);
+
+        let mut request = update(&[("db", "/nix/store/db")]);
+        request.selector = Some("role=web".parse().unwrap());
+        state.resolve_selector(&mut request.clone()).unwrap_err();
+
+        request.store_path = Some("/nix/store/web".to_owned());
+        state.resolve_selector(&mut request).unwrap();
+        state.update_hosts(request).unwrap();
+        assert_eq!(provisioned(&state, "web-1"), Some("/nix/store/web"));
+        assert_eq!(provisioned(&state, "web-2"), Some("/nix/store/web"));
+        assert_eq!(provisioned(&state, "db"), Some("/nix/store/db"));
+
+        // Only a selector
+        let mut request = update(&[]);
+        request.selector = Some("role=db".parse().unwrap());
+        request.store_path = Some("/nix/store/db-2".to_owned());
+        state.resolve_selector(&mut request).unwrap();
+        state.update_hosts(request).unwrap();
+        assert_eq!(provisioned(&state, "db"), Some("/nix/store/db-2"));
+        assert_eq!(provisioned(&state, "web-1"), Some("/nix/store/web"));
+    }
+
+    #[test]
+    fn test_publish_unknown_host() {
+        let mut state = AppState::default();
+        state
+            .create_placeholder(api::PlaceholderRequest {
+                hostname: "web-3".to_owned(),
+                tags: api::Tags::default(),
+            })
+            .unwrap();
+
+        let mut request = update(&[("web-3", "/nix/store/web"), ("web-4", "/nix/store/web")]);
+        let rejected = state.reject_hosts(&key(10), &mut request.hosts);
+        assert_eq!(
+            rejected.into_iter().collect::<Vec<_>>(),
+            [("web-4".to_owned(), api::UpdateRejection::UnknownHost)]
+        );
+        state.update_hosts(request).unwrap();
+
+        let placeholders = state.placeholders();
+        assert_eq!(placeholders.len(), 1);
+        let store_path = placeholders[0].provision_state.store_path();
+        assert_eq!(store_path.map(String::as_str), Some("/nix/store/web"));
+    }
+
+    #[test]
+    fn test_hostname_taken() {
+        let mut state = AppState::default();
+        add_host(&mut state, 1, "web-1", &[]);
+        let code = state.add_verification_attempt(attempt(2)).unwrap();
+        assert!(matches!(
+            state.verify_attempt(api::VerificationAcceptance {
+                code,
+                hostname: "web-1".to_owned(),
+            }),
+            Err(StateError::HostnameTaken(_))
+        ));
+        // The attempt can still be approved under another name
+        state
+            .verify_attempt(api::VerificationAcceptance {
+                code,
+                hostname: "web-2".to_owned(),
+            })
+            .unwrap();
+        assert_eq!(state.host_by_key[&key(1)], "web-1");
+    }
+}
@@ -0,0 +1,82 @@
+//! Persistence backends for the [`AppState`](crate::state::AppState)
+//!
+//! The state is handed to the backends as a [`serde_json::Value`] so that a backend can decide
+//! on its own how fine grained it wants to persist the state.
+
+use std::{io, path::Path, str::FromStr};
+
+use serde_json::Value;
+use thiserror::Error;
+
+pub mod json;
+pub mod sqlite;
+
+#[derive(Error, Debug)]
+pub enum StoreError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("State has to be serialized as a JSON object")]
+    NotAnObject,
+
+    #[error("Unknown storage backend `{0}` - supported are `json` and `sqlite`")]
+    UnknownBackend(String),
+}
+
+pub trait StateStore: Send {
+    /// Load the persisted state. Returns `None` if nothing was persisted yet
+    fn load(&mut self) -> Result<Option<Value>, StoreError>;
+
+    /// Persist the state. Backends are expected to skip writes if nothing changed
+    /// because this is called in a short interval
+    fn save(&mut self, state: &Value) -> Result<(), StoreError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Whole state as a single pretty printed JSON file
+    #[default]
+    Json,
+    /// Embedded SQLite database with one row per host
+    Sqlite,
+}
+
+impl Backend {
+    pub fn default_location(self) -> &'static str {
+        match self {
+            Backend::Json => "state.json",
+            Backend::Sqlite => "state.sqlite",
+        }
+    }
+
+    /// `snapshots` is the number of old versions to keep around.
+    /// SQLite is transactional on its own so snapshots only apply to the JSON backend.
+    pub fn open<P: AsRef<Path>>(
+        self,
+        location: P,
+        snapshots: usize,
+    ) -> Result<Box<dyn StateStore>, StoreError> {
+        Ok(match self {
+            Backend::Json => Box::new(json::JsonStore::open(location, snapshots)?),
+            Backend::Sqlite => Box::new(sqlite::SqliteStore::open(location)?),
+        })
+    }
+}
+
+impl FromStr for Backend {
+    type Err = StoreError;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend.to_lowercase().as_str() {
+            "json" => Ok(Backend::Json),
+            "sqlite" => Ok(Backend::Sqlite),
+            _ => Err(StoreError::UnknownBackend(backend.to_owned())),
+        }
+    }
+}
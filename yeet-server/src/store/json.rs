@@ -0,0 +1,162 @@
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+
+use crate::store::{StateStore, StoreError};
+
+/// Saves happen every few hundred milliseconds while hosts ping. Snapshotting each of them would
+/// only keep the last seconds around
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Stores the whole state in a single JSON file.
+/// Every change rewrites the whole file.
+///
+/// Writes are crash safe: the state is written to a temporary file which is synced
+/// and then renamed over the old state. The state found on startup and then the previous
+/// version at most once per hour are kept as snapshots (`state.json.1` being the newest).
+pub struct JsonStore {
+    path: PathBuf,
+    snapshots: usize,
+    hash: u64,
+    /// `None` until the first snapshot since startup
+    last_snapshot: Option<Instant>,
+}
+
+impl JsonStore {
+    pub fn open<P: AsRef<Path>>(location: P, snapshots: usize) -> Result<Self, StoreError> {
+        let path = location.as_ref().to_path_buf();
+        // Fail early instead of on the first write
+        if !directory(&path).is_dir() {
+            return Err(io::Error::from(io::ErrorKind::NotFound).into());
+        }
+        Ok(Self {
+            path,
+            snapshots,
+            hash: 0,
+            last_snapshot: None,
+        })
+    }
+
+    fn with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+
+    /// Shift every snapshot by one and keep the current state as the newest snapshot.
+    /// Does nothing if the last snapshot is younger than [`SNAPSHOT_INTERVAL`]
+    fn rotate_snapshots(&mut self, now: Instant) -> Result<(), StoreError> {
+        if self.snapshots == 0 || !self.path.exists() {
+            return Ok(());
+        }
+        if self
+            .last_snapshot
+            .is_some_and(|last| now.duration_since(last) < SNAPSHOT_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        for index in (1..self.snapshots).rev() {
+            let snapshot = self.with_suffix(&format!(".{index}"));
+            if snapshot.exists() {
+                fs::rename(&snapshot, self.with_suffix(&format!(".{}", index + 1)))?;
+            }
+        }
+
+        let newest = self.with_suffix(".1");
+        if newest.exists() {
+            fs::remove_file(&newest)?;
+        }
+        // The state itself gets replaced by a rename so linking is enough to keep the old version
+        fs::hard_link(&self.path, newest)?;
+        self.last_snapshot = Some(now);
+        Ok(())
+    }
+}
+
+impl StateStore for JsonStore {
+    fn load(&mut self) -> Result<Option<Value>, StoreError> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if data.is_empty() {
+            return Ok(None);
+        }
+        self.hash = api::hash(&data);
+        Ok(Some(serde_json::from_slice(&data)?))
+    }
+
+    fn save(&mut self, state: &Value) -> Result<(), StoreError> {
+        let data = serde_json::to_vec_pretty(state)?;
+        let hash = api::hash(&data);
+
+        if self.hash == hash {
+            return Ok(());
+        }
+
+        let tmp = self.with_suffix(".tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&data)?;
+            file.sync_all()?;
+        }
+
+        self.rotate_snapshots(Instant::now())?;
+        fs::rename(&tmp, &self.path)?;
+        // Persist the rename itself
+        File::open(directory(&self.path))?.sync_all()?;
+
+        self.hash = hash;
+        Ok(())
+    }
+}
+
+fn directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod test_json_store {
+    use std::{
+        fs,
+        time::{Duration, Instant},
+    };
+
+    use serde_json::json;
+
+    use crate::store::{StateStore as _, json::JsonStore};
+
+    #[test]
+    fn test_snapshot_interval() {
+        let dir = std::env::temp_dir().join(format!("yeet-json-store-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        let mut store = JsonStore::open(&path, 5).unwrap();
+
+        for version in 0..3 {
+            store.save(&json!({ "version": version })).unwrap();
+        }
+        // Only the state before the first write since startup is kept
+        let snapshot: serde_json::Value =
+            serde_json::from_slice(&fs::read(dir.join("state.json.1")).unwrap()).unwrap();
+        assert_eq!(snapshot, json!({ "version": 0 }));
+        assert!(!dir.join("state.json.2").exists());
+
+        let later = Instant::now() + Duration::from_secs(60 * 60);
+        store.rotate_snapshots(later).unwrap();
+        assert!(dir.join("state.json.2").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
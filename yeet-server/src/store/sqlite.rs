@@ -0,0 +1,121 @@
+use std::{collections::HashMap, path::Path};
+
+use rusqlite::{Connection, Transaction, params};
+use serde_json::{Map, Value};
+
+use crate::store::{StateStore, StoreError};
+
+/// Field of the state which gets its own table with one row per host
+const HOSTS: &str = "hosts";
+
+/// Stores the state in an embedded SQLite database.
+/// Each host is a separate row and every other field of the state is a row in the `state` table.
+/// Only rows which changed since the last save are written, so a ping only touches a single host.
+pub struct SqliteStore {
+    conn: Connection,
+    // key -> hash of the last written data
+    state_rows: HashMap<String, u64>,
+    // hostname -> hash of the last written data
+    host_rows: HashMap<String, u64>,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(location: P) -> Result<Self, StoreError> {
+        let conn = Connection::open(location)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             PRAGMA synchronous = FULL;
+             CREATE TABLE IF NOT EXISTS state (key TEXT PRIMARY KEY NOT NULL, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS hosts (key TEXT PRIMARY KEY NOT NULL, data TEXT NOT NULL);",
+        )?;
+        Ok(Self {
+            conn,
+            state_rows: HashMap::new(),
+            host_rows: HashMap::new(),
+        })
+    }
+}
+
+impl StateStore for SqliteStore {
+    fn load(&mut self) -> Result<Option<Value>, StoreError> {
+        let mut state = read_table(&self.conn, "state", &mut self.state_rows)?;
+        if state.is_empty() {
+            return Ok(None);
+        }
+        let hosts = read_table(&self.conn, "hosts", &mut self.host_rows)?;
+        state.insert(HOSTS.to_owned(), Value::Object(hosts));
+        Ok(Some(Value::Object(state)))
+    }
+
+    fn save(&mut self, state: &Value) -> Result<(), StoreError> {
+        let Value::Object(state) = state else {
+            return Err(StoreError::NotAnObject);
+        };
+
+        let mut state = state.clone();
+        let hosts = match state.remove(HOSTS) {
+            Some(Value::Object(hosts)) => hosts,
+            _ => Map::new(),
+        };
+
+        let tx = self.conn.transaction()?;
+        let state_rows = write_table(&tx, "state", &state, &self.state_rows)?;
+        let host_rows = write_table(&tx, "hosts", &hosts, &self.host_rows)?;
+        tx.commit()?;
+
+        // Only remember what was written once the transaction went through
+        self.state_rows = state_rows;
+        self.host_rows = host_rows;
+        Ok(())
+    }
+}
+
+fn read_table(
+    conn: &Connection,
+    table: &str,
+    written: &mut HashMap<String, u64>,
+) -> Result<Map<String, Value>, StoreError> {
+    let mut stmt = conn.prepare(&format!("SELECT key, data FROM {table}"))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+
+    let mut map = Map::new();
+    for row in rows {
+        let (key, data) = row?;
+        written.insert(key.clone(), api::hash(&data));
+        map.insert(key, serde_json::from_str(&data)?);
+    }
+    Ok(map)
+}
+
+/// Upserts every row whose data changed and deletes rows which no longer exist.
+/// Returns the hashes of all rows now stored in the table.
+fn write_table(
+    tx: &Transaction<'_>,
+    table: &str,
+    rows: &Map<String, Value>,
+    written: &HashMap<String, u64>,
+) -> Result<HashMap<String, u64>, StoreError> {
+    let mut upsert = tx.prepare_cached(&format!(
+        "INSERT INTO {table} (key, data) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET data = excluded.data"
+    ))?;
+
+    let mut hashes = HashMap::with_capacity(rows.len());
+    for (key, value) in rows {
+        let data = serde_json::to_string(value)?;
+        let hash = api::hash(&data);
+        if written.get(key) != Some(&hash) {
+            upsert.execute(params![key, data])?;
+        }
+        hashes.insert(key.clone(), hash);
+    }
+
+    let mut delete = tx.prepare_cached(&format!("DELETE FROM {table} WHERE key = ?1"))?;
+    for key in written.keys().filter(|key| !rows.contains_key(*key)) {
+        delete.execute(params![key])?;
+    }
+
+    Ok(hashes)
+}
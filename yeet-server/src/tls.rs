@@ -0,0 +1,33 @@
+//! Optional TLS termination with rustls.
+//!
+//! Terminating TLS in yeetd keeps the `@path` signature component intact, which a reverse proxy
+//! with a path prefix would rewrite. The certificate is read again on `SIGHUP` so that renewed
+//! certificates are used without a restart.
+
+use std::path::PathBuf;
+
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::signal::unix::{SignalKind, signal};
+
+#[expect(
+    clippy::print_stdout,
+    clippy::print_stderr,
+    reason = "yeetd has no logger"
+)]
+pub async fn reload_on_hangup(config: RustlsConfig, cert: PathBuf, key: PathBuf) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            eprintln!("Could not install the SIGHUP handler - TLS reload is disabled: {err}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        match config.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => println!("Reloaded TLS certificate {}", cert.display()),
+            // A half written certificate must not take the server down
+            Err(err) => eprintln!("Could not reload TLS certificate - keeping the old one: {err}"),
+        }
+    }
+}
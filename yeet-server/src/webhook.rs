@@ -0,0 +1,268 @@
+//! Outbound webhooks for fleet events.
+//!
+//! State changes queue an [`api::webhook::Event`] in the [`Outbox`] of the
+//! [`AppState`]. The outbox is persisted together with the rest of the state so no event is lost
+//! on a restart. [`deliver`] fans every event out to all configured URLs and retries failed
+//! deliveries with an exponential backoff until [`MAX_ATTEMPTS`] is reached.
+
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
+
+use api::{
+    httpsig::ReqwestSig as _,
+    webhook::{Event, WebhookEvent},
+};
+use httpsig_hyper::prelude::{HttpSignatureParams, SecretKey, message_component};
+use jiff::{SignedDuration, Zoned};
+use parking_lot::RwLock;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::time::interval;
+use uuid::Uuid;
+
+use crate::state::AppState;
+
+/// A delivery is dropped after this many failed attempts - roughly a day with [`backoff`]
+const MAX_ATTEMPTS: u32 = 30;
+const BASE_BACKOFF: SignedDuration = SignedDuration::from_secs(10);
+const MAX_BACKOFF: SignedDuration = SignedDuration::from_hours(1);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    /// Signs the events. Always set if there are URLs
+    pub key: Option<SecretKey>,
+    /// Send [`Event::HostStale`] if a host did not check in for this long
+    pub stale_after: SignedDuration,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct Delivery {
+    url: String,
+    event: WebhookEvent,
+    attempts: u32,
+    next_attempt: Zoned,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Default, Debug)]
+pub struct Outbox {
+    /// Events which are not yet assigned to the webhook URLs
+    events: Vec<WebhookEvent>,
+    deliveries: Vec<Delivery>,
+    /// Hosts [`Event::HostStale`] was sent for. Removed again once the host checks in
+    stale: BTreeSet<String>,
+}
+
+impl Outbox {
+    pub fn emit(&mut self, event: Event) {
+        self.events.push(WebhookEvent {
+            id: Uuid::now_v7(),
+            time: Zoned::now(),
+            event,
+        });
+    }
+
+    /// Queue [`Event::HostStale`] once for every host which has not checked in since `stale_after`
+    pub fn check_stale<'a>(
+        &mut self,
+        hosts: impl Iterator<Item = &'a api::Host>,
+        stale_after: SignedDuration,
+        now: &Zoned,
+    ) {
+        let mut seen = BTreeSet::new();
+        for host in hosts {
+            let stale = now.duration_since(&host.last_ping) > stale_after;
+            if !stale {
+                continue;
+            }
+            seen.insert(host.name.clone());
+            if !self.stale.contains(&host.name) {
+                self.emit(Event::HostStale {
+                    hostname: host.name.clone(),
+                    last_ping: host.last_ping.clone(),
+                });
+            }
+        }
+        // Hosts which checked in again or were removed can go stale again
+        self.stale = seen;
+    }
+
+    /// Create a delivery for every URL. Without URLs the events are dropped
+    fn fan_out(&mut self, urls: &[String]) {
+        for event in self.events.drain(..) {
+            self.deliveries
+                .extend(urls.iter().map(|url| Delivery {
+                    url: url.clone(),
+                    event: event.clone(),
+                    attempts: 0,
+                    next_attempt: event.time.clone(),
+                }));
+        }
+    }
+
+    fn due(&self, now: &Zoned) -> Vec<Delivery> {
+        self.deliveries
+            .iter()
+            .filter(|delivery| delivery.next_attempt <= *now)
+            .cloned()
+            .collect()
+    }
+
+    fn delivered(&mut self, delivered: &Delivery) {
+        self.deliveries.retain(|delivery| {
+            delivery.url != delivered.url || delivery.event.id != delivered.event.id
+        });
+    }
+
+    /// Schedule the next attempt. Returns false if the delivery was dropped
+    fn failed(&mut self, failed: &Delivery, now: &Zoned) -> bool {
+        let Some(delivery) = self.deliveries.iter_mut().find(|delivery| {
+            delivery.url == failed.url && delivery.event.id == failed.event.id
+        }) else {
+            return false;
+        };
+        delivery.attempts += 1;
+        if delivery.attempts >= MAX_ATTEMPTS {
+            self.delivered(failed);
+            return false;
+        }
+        delivery.next_attempt = now
+            .checked_add(backoff(delivery.attempts))
+            .unwrap_or_else(|_| now.clone());
+        true
+    }
+}
+
+/// Doubles with every attempt up to [`MAX_BACKOFF`]
+fn backoff(attempts: u32) -> SignedDuration {
+    2_i32
+        .checked_pow(attempts.saturating_sub(1))
+        .and_then(|factor| BASE_BACKOFF.checked_mul(factor))
+        .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+}
+
+/// Deliver queued events as long as the server is running
+#[expect(clippy::print_stderr, reason = "yeetd has no logger")]
+pub async fn deliver(state: Arc<RwLock<AppState>>, config: WebhookConfig) {
+    let client = Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .unwrap_or_default();
+    let mut interval = interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+        let now = Zoned::now();
+
+        let due = {
+            let mut state = state.write();
+            state.check_stale_hosts(config.stale_after, &now);
+            let outbox = state.outbox();
+            outbox.fan_out(&config.urls);
+            outbox.due(&now)
+        };
+
+        let Some(key) = &config.key else {
+            continue;
+        };
+        // Do not hold the lock while waiting on the receivers
+        for delivery in due {
+            let result = send(&client, key, &delivery).await;
+
+            let mut state = state.write();
+            match result {
+                Ok(()) => state.outbox().delivered(&delivery),
+                Err(err) => {
+                    if !state.outbox().failed(&delivery, &Zoned::now()) {
+                        eprintln!(
+                            "Dropping webhook event {} to {} after {MAX_ATTEMPTS} attempts: {err}",
+                            delivery.event.id, delivery.url
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn send(client: &Client, key: &SecretKey, delivery: &Delivery) -> Result<(), String> {
+    let components = ["@method", "@target-uri", "content-digest"]
+        .iter()
+        .map(|component| message_component::HttpMessageComponentId::try_from(*component))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| err.to_string())?;
+    let mut params = HttpSignatureParams::try_new(&components).map_err(|err| err.to_string())?;
+    params.set_key_info(key);
+
+    client
+        .post(&delivery.url)
+        .json(&delivery.event)
+        .sign(&params, key)
+        .await
+        .map_err(|err| err.to_string())?
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_webhook {
+    use api::webhook::Event;
+    use jiff::{SignedDuration, Zoned};
+
+    use crate::webhook::{MAX_ATTEMPTS, MAX_BACKOFF, Outbox, backoff};
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), SignedDuration::from_secs(10));
+        assert_eq!(backoff(3), SignedDuration::from_secs(40));
+        assert_eq!(backoff(MAX_ATTEMPTS), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_retry_until_dropped() {
+        let mut outbox = Outbox::default();
+        outbox.emit(Event::Attached {
+            hostname: "web".to_owned(),
+        });
+        outbox.fan_out(&["https://a".to_owned(), "https://b".to_owned()]);
+
+        let now = Zoned::now();
+        let due = outbox.due(&now);
+        assert_eq!(due.len(), 2);
+
+        outbox.delivered(&due[0]);
+        assert!(outbox.failed(&due[1], &now));
+        assert!(outbox.due(&now).is_empty());
+
+        for _ in 1..MAX_ATTEMPTS - 1 {
+            assert!(outbox.failed(&due[1], &now));
+        }
+        assert!(!outbox.failed(&due[1], &now));
+        assert!(outbox.deliveries.is_empty());
+    }
+
+    #[test]
+    fn test_stale_once() {
+        let mut outbox = Outbox::default();
+        let host = api::Host {
+            name: "web".to_owned(),
+            last_ping: Zoned::now().checked_sub(SignedDuration::from_mins(30)).unwrap(),
+            ..Default::default()
+        };
+        let stale_after = SignedDuration::from_mins(10);
+
+        outbox.check_stale([&host].into_iter(), stale_after, &Zoned::now());
+        outbox.check_stale([&host].into_iter(), stale_after, &Zoned::now());
+        assert_eq!(outbox.events.len(), 1);
+
+        let fresh = api::Host {
+            last_ping: Zoned::now(),
+            ..host.clone()
+        };
+        outbox.check_stale([&fresh].into_iter(), stale_after, &Zoned::now());
+        outbox.check_stale([&host].into_iter(), stale_after, &Zoned::now());
+        assert_eq!(outbox.events.len(), 2);
+    }
+}